@@ -0,0 +1,665 @@
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::{
+    error::{NetError, ParseError, ProtocolError},
+    instruction::{self, Instruction, MAX_KEY_LENGTH},
+    response::Response,
+    slabs,
+};
+
+pub const REQUEST_MAGIC: u8 = 0x80;
+const RESPONSE_MAGIC: u8 = 0x81;
+const HEADER_SIZE: usize = 24;
+// A packet carrying the largest value there is room for, with a key and extras
+const MAX_BODY_LENGTH: usize = slabs::PAGE_SIZE + MAX_KEY_LENGTH + u8::MAX as usize;
+
+const GET: u8 = 0x00;
+const SET: u8 = 0x01;
+const ADD: u8 = 0x02;
+const REPLACE: u8 = 0x03;
+const DELETE: u8 = 0x04;
+const INCREMENT: u8 = 0x05;
+const DECREMENT: u8 = 0x06;
+const QUIT: u8 = 0x07;
+const FLUSH: u8 = 0x08;
+const GETQ: u8 = 0x09;
+const NOOP: u8 = 0x0a;
+const VERSION: u8 = 0x0b;
+const GETK: u8 = 0x0c;
+const GETKQ: u8 = 0x0d;
+const APPEND: u8 = 0x0e;
+const PREPEND: u8 = 0x0f;
+const STAT: u8 = 0x10;
+const SETQ: u8 = 0x11;
+const ADDQ: u8 = 0x12;
+const REPLACEQ: u8 = 0x13;
+const DELETEQ: u8 = 0x14;
+const INCREMENTQ: u8 = 0x15;
+const DECREMENTQ: u8 = 0x16;
+const QUITQ: u8 = 0x17;
+const FLUSHQ: u8 = 0x18;
+const APPENDQ: u8 = 0x19;
+const PREPENDQ: u8 = 0x1a;
+const VERBOSITY: u8 = 0x1b;
+const TOUCH: u8 = 0x1c;
+const GAT: u8 = 0x1d;
+const GATQ: u8 = 0x1e;
+const GATK: u8 = 0x23;
+const GATKQ: u8 = 0x24;
+
+const STATUS_OK: u16 = 0x0000;
+const STATUS_KEY_NOT_FOUND: u16 = 0x0001;
+const STATUS_KEY_EXISTS: u16 = 0x0002;
+const STATUS_VALUE_TOO_LARGE: u16 = 0x0003;
+const STATUS_INVALID_ARGUMENTS: u16 = 0x0004;
+const STATUS_NOT_STORED: u16 = 0x0005;
+const STATUS_NON_NUMERIC: u16 = 0x0006;
+const STATUS_UNKNOWN_COMMAND: u16 = 0x0081;
+const STATUS_OUT_OF_MEMORY: u16 = 0x0082;
+const STATUS_INTERNAL_ERROR: u16 = 0x0084;
+
+// Expiration of an increment or decrement that must not create the counter
+const NO_CREATE: u32 = 0xffffffff;
+
+/// A binary protocol request packet.
+#[derive(Debug)]
+pub struct Packet {
+    opcode: u8,
+    opaque: u32,
+    cas: u64,
+    extras: Bytes,
+    key: Bytes,
+    value: Bytes,
+}
+
+/// What the reply to a request needs to know about it.
+#[derive(Debug, Clone)]
+pub struct Request {
+    opcode: u8,
+    opaque: u32,
+    // Sent back by the get commands returning the key, even on a miss
+    key: Bytes,
+}
+
+/// Takes the next whole packet off the buffer, None if more bytes are needed.
+pub fn parse_packet(buffer: &mut BytesMut) -> Result<Option<Packet>> {
+    if buffer.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+    if buffer[0] != REQUEST_MAGIC {
+        // There is no way to find the start of the next packet
+        anyhow::bail!(NetError::InvalidPacket)
+    }
+    let mut header = &buffer[..HEADER_SIZE];
+    header.advance(1);
+    let opcode = header.get_u8();
+    let key_length = header.get_u16() as usize;
+    let extras_length = header.get_u8() as usize;
+    // Data type and vbucket
+    header.advance(3);
+    let body_length = header.get_u32() as usize;
+    let opaque = header.get_u32();
+    let cas = header.get_u64();
+    if extras_length + key_length > body_length {
+        anyhow::bail!(NetError::InvalidPacket)
+    }
+    // A body too large to ever store is not buffered
+    if body_length > MAX_BODY_LENGTH {
+        buffer.advance(HEADER_SIZE);
+        let request = Request {
+            opcode,
+            opaque,
+            key: Bytes::new(),
+        };
+        anyhow::bail!(ParseError::PacketTooLarge(request, body_length))
+    }
+    if buffer.len() < HEADER_SIZE + body_length {
+        return Ok(None);
+    }
+
+    buffer.advance(HEADER_SIZE);
+    let mut body = buffer.split_to(body_length).freeze();
+    let extras = body.split_to(extras_length);
+    let key = body.split_to(key_length);
+    Ok(Some(Packet {
+        opcode,
+        opaque,
+        cas,
+        extras,
+        key,
+        value: body,
+    }))
+}
+
+impl Packet {
+    pub fn request(&self) -> Request {
+        Request {
+            opcode: self.opcode,
+            opaque: self.opaque,
+            key: self.key.clone(),
+        }
+    }
+
+    pub fn is_noop(&self) -> bool {
+        self.opcode == NOOP
+    }
+
+    /// Maps the packet onto the instruction the text protocol would use.
+    pub fn into_instruction(self) -> Result<Instruction> {
+        let mut extras = self.extras.clone();
+        match self.opcode {
+            GET | GETQ | GETK | GETKQ => Ok(Instruction::Get {
+                keys: vec![self.key()?],
+            }),
+            GAT | GATQ | GATK | GATKQ => Ok(Instruction::Gat {
+                expiry: read_u32(&mut extras)?.into(),
+                keys: vec![self.key()?],
+            }),
+            SET | SETQ | ADD | ADDQ | REPLACE | REPLACEQ => {
+                let flags = read_u32(&mut extras)?;
+                let expiry = read_u32(&mut extras)?.into();
+                let key = self.key()?;
+                let data_size = self.value.len();
+                let data = self.value;
+                Ok(match self.opcode {
+                    // A set or replace with a CAS only replaces the item it was read as
+                    SET | SETQ | REPLACE | REPLACEQ if self.cas != 0 => Instruction::Cas {
+                        key,
+                        flags,
+                        expiry,
+                        data_size,
+                        data,
+                        cas_unique: self.cas,
+                        noreply: false,
+                    },
+                    SET | SETQ => Instruction::Set {
+                        key,
+                        flags,
+                        expiry,
+                        data_size,
+                        data,
+                        noreply: false,
+                    },
+                    ADD | ADDQ if self.cas != 0 => {
+                        anyhow::bail!(ProtocolError::ClientError(
+                            "CAS is not supported for add".to_owned()
+                        ))
+                    }
+                    ADD | ADDQ => Instruction::Add {
+                        key,
+                        flags,
+                        expiry,
+                        data_size,
+                        data,
+                        noreply: false,
+                    },
+                    _ => Instruction::Replace {
+                        key,
+                        flags,
+                        expiry,
+                        data_size,
+                        data,
+                        noreply: false,
+                    },
+                })
+            }
+            APPEND | APPENDQ | PREPEND | PREPENDQ => {
+                if self.cas != 0 {
+                    anyhow::bail!(ProtocolError::ClientError(
+                        "CAS is not supported for append and prepend".to_owned()
+                    ));
+                }
+                let key = self.key()?;
+                let data_size = self.value.len();
+                let data = self.value;
+                Ok(match self.opcode {
+                    APPEND | APPENDQ => Instruction::Append {
+                        key,
+                        flags: 0,
+                        expiry: 0,
+                        data_size,
+                        data,
+                        noreply: false,
+                    },
+                    _ => Instruction::Prepend {
+                        key,
+                        flags: 0,
+                        expiry: 0,
+                        data_size,
+                        data,
+                        noreply: false,
+                    },
+                })
+            }
+            DELETE | DELETEQ => Ok(Instruction::Delete {
+                key: self.key()?,
+                cas_unique: (self.cas != 0).then_some(self.cas),
+                noreply: false,
+            }),
+            INCREMENT | INCREMENTQ | DECREMENT | DECREMENTQ => {
+                let key = self.key()?;
+                let delta = read_u64(&mut extras)?;
+                let initial = read_u64(&mut extras)?;
+                let expiry = read_u32(&mut extras)?;
+                let initial = if expiry == NO_CREATE {
+                    None
+                } else {
+                    Some((initial, expiry.into()))
+                };
+                Ok(match self.opcode {
+                    INCREMENT | INCREMENTQ => Instruction::Incr {
+                        key,
+                        delta,
+                        initial,
+                        noreply: false,
+                    },
+                    _ => Instruction::Decr {
+                        key,
+                        delta,
+                        initial,
+                        noreply: false,
+                    },
+                })
+            }
+            TOUCH => Ok(Instruction::Touch {
+                expiry: read_u32(&mut extras)?.into(),
+                key: self.key()?,
+                noreply: false,
+            }),
+            FLUSH | FLUSHQ => {
+                // The delay is optional
+                let delay = if extras.is_empty() {
+                    0
+                } else {
+                    read_u32(&mut extras)? as u128
+                };
+                Ok(Instruction::FlushAll {
+                    delay,
+                    noreply: false,
+                })
+            }
+            VERBOSITY => Ok(Instruction::Verbosity {
+                level: read_u32(&mut extras)?,
+                noreply: false,
+            }),
+            STAT => {
+                let group = if self.key.is_empty() {
+                    None
+                } else {
+                    Some(self.key()?)
+                };
+                Ok(Instruction::Stats { group })
+            }
+            VERSION => Ok(Instruction::Version),
+            QUIT | QUITQ => Ok(Instruction::Quit),
+            _ => Err(anyhow!(ParseError::UnknownCommand)),
+        }
+    }
+
+    fn key(&self) -> Result<String> {
+        match String::from_utf8(self.key.to_vec()) {
+            Ok(key) if instruction::is_valid_key(&key) => Ok(key),
+            _ => Err(anyhow!(ParseError::InvalidInstruction)),
+        }
+    }
+}
+
+impl Request {
+    /// Quiet requests only get a reply when it carries news: a hit for the
+    /// quiet gets and a failure for the others.
+    fn quiet(&self) -> bool {
+        matches!(
+            self.opcode,
+            GETQ | GETKQ
+                | GATQ
+                | GATKQ
+                | SETQ
+                | ADDQ
+                | REPLACEQ
+                | DELETEQ
+                | INCREMENTQ
+                | DECREMENTQ
+                | QUITQ
+                | FLUSHQ
+                | APPENDQ
+                | PREPENDQ
+        )
+    }
+
+    fn is_get(&self) -> bool {
+        matches!(
+            self.opcode,
+            GET | GETQ | GETK | GETKQ | GAT | GATQ | GATK | GATKQ
+        )
+    }
+
+    fn returns_key(&self) -> bool {
+        matches!(self.opcode, GETK | GETKQ | GATK | GATKQ)
+    }
+}
+
+/// The packets answering the request, none if it is quiet and went well.
+pub fn encode_response(request: &Request, response: &Response) -> Vec<Bytes> {
+    let success = |cas: u64| {
+        if request.quiet() && !request.is_get() {
+            Vec::new()
+        } else {
+            packet(request, STATUS_OK, cas, &[], &[], Bytes::new())
+        }
+    };
+    match response {
+        Response::Values { values, .. } => match values.first() {
+            Some(value) => {
+                let key: &[u8] = if request.returns_key() {
+                    value.key.as_bytes()
+                } else {
+                    &[]
+                };
+                packet(
+                    request,
+                    STATUS_OK,
+                    value.cas,
+                    &value.flags.to_be_bytes(),
+                    key,
+                    value.data.clone(),
+                )
+            }
+            None if request.quiet() => Vec::new(),
+            // Like memcached, the body of a keyed miss is the key alone
+            None if request.returns_key() => packet(
+                request,
+                STATUS_KEY_NOT_FOUND,
+                0,
+                &[],
+                &request.key,
+                Bytes::new(),
+            ),
+            None => status(request, STATUS_KEY_NOT_FOUND, "Not found"),
+        },
+        Response::Stored { cas } => success(*cas),
+        Response::Deleted | Response::Touched | Response::Ok => success(0),
+        Response::NotStored => match request.opcode {
+            ADD | ADDQ => status(request, STATUS_KEY_EXISTS, "Data exists for key"),
+            REPLACE | REPLACEQ => status(request, STATUS_KEY_NOT_FOUND, "Not found"),
+            _ => status(request, STATUS_NOT_STORED, "Not stored"),
+        },
+        Response::Exists => status(request, STATUS_KEY_EXISTS, "Data exists for key"),
+        Response::NotFound => status(request, STATUS_KEY_NOT_FOUND, "Not found"),
+        Response::Counter { value, cas } => {
+            if request.quiet() {
+                return Vec::new();
+            }
+            let value = String::from_utf8_lossy(value).parse::<u64>().unwrap_or(0);
+            packet(
+                request,
+                STATUS_OK,
+                *cas,
+                &[],
+                &[],
+                Bytes::copy_from_slice(&value.to_be_bytes()),
+            )
+        }
+        Response::Line(line) => encode_line(request, line),
+        // Meta commands are only read from text connections
+        Response::Meta { .. } | Response::Quiet => Vec::new(),
+    }
+}
+
+pub fn encode_error(request: &Request, error: &ProtocolError) -> Vec<Bytes> {
+    match error {
+        ProtocolError::UnknownCommand => status(request, STATUS_UNKNOWN_COMMAND, "Unknown command"),
+        ProtocolError::ClientError(msg) => status(request, STATUS_INVALID_ARGUMENTS, msg),
+        ProtocolError::ServerError(msg) => status(request, STATUS_INTERNAL_ERROR, msg),
+        ProtocolError::NonNumeric => status(
+            request,
+            STATUS_NON_NUMERIC,
+            "Non-numeric server-side value for incr or decr",
+        ),
+        ProtocolError::TooLarge => status(request, STATUS_VALUE_TOO_LARGE, "Too large"),
+        ProtocolError::OutOfMemory => status(request, STATUS_OUT_OF_MEMORY, "Out of memory"),
+    }
+}
+
+/// Replies to the commands whose text reply is a single line.
+fn encode_line(request: &Request, line: &Bytes) -> Vec<Bytes> {
+    let text = String::from_utf8_lossy(line);
+    match request.opcode {
+        VERSION => {
+            let version = text.trim_start_matches("VERSION ").to_owned();
+            packet(request, STATUS_OK, 0, &[], &[], Bytes::from(version))
+        }
+        STAT => {
+            // One packet per stat, ended by one without a key
+            let mut frames = Vec::new();
+            for stat in text.lines() {
+                let mut parts = stat.splitn(3, ' ');
+                if let (Some("STAT"), Some(name), Some(value)) =
+                    (parts.next(), parts.next(), parts.next())
+                {
+                    frames.extend(packet(
+                        request,
+                        STATUS_OK,
+                        0,
+                        &[],
+                        name.as_bytes(),
+                        Bytes::copy_from_slice(value.as_bytes()),
+                    ));
+                }
+            }
+            frames.extend(packet(request, STATUS_OK, 0, &[], &[], Bytes::new()));
+            frames
+        }
+        _ => packet(request, STATUS_OK, 0, &[], &[], line.clone()),
+    }
+}
+
+fn status(request: &Request, status: u16, message: &str) -> Vec<Bytes> {
+    packet(
+        request,
+        status,
+        0,
+        &[],
+        &[],
+        Bytes::copy_from_slice(message.as_bytes()),
+    )
+}
+
+/// A response packet as two frames, the header with extras and key, then the value.
+fn packet(
+    request: &Request,
+    status: u16,
+    cas: u64,
+    extras: &[u8],
+    key: &[u8],
+    value: Bytes,
+) -> Vec<Bytes> {
+    let mut header = BytesMut::with_capacity(HEADER_SIZE + extras.len() + key.len());
+    header.put_u8(RESPONSE_MAGIC);
+    header.put_u8(request.opcode);
+    header.put_u16(key.len() as u16);
+    header.put_u8(extras.len() as u8);
+    // Raw bytes data type
+    header.put_u8(0);
+    header.put_u16(status);
+    header.put_u32((extras.len() + key.len() + value.len()) as u32);
+    header.put_u32(request.opaque);
+    header.put_u64(cas);
+    header.put_slice(extras);
+    header.put_slice(key);
+    vec![header.freeze(), value]
+}
+
+fn read_u32(extras: &mut Bytes) -> Result<u32> {
+    if extras.remaining() < 4 {
+        anyhow::bail!(ParseError::InvalidInstruction)
+    }
+    Ok(extras.get_u32())
+}
+
+fn read_u64(extras: &mut Bytes) -> Result<u64> {
+    if extras.remaining() < 8 {
+        anyhow::bail!(ParseError::InvalidInstruction)
+    }
+    Ok(extras.get_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::Value;
+
+    fn request_bytes(opcode: u8, cas: u64, extras: &[u8], key: &[u8], value: &[u8]) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        buffer.put_u8(REQUEST_MAGIC);
+        buffer.put_u8(opcode);
+        buffer.put_u16(key.len() as u16);
+        buffer.put_u8(extras.len() as u8);
+        buffer.put_u8(0);
+        buffer.put_u16(0);
+        buffer.put_u32((extras.len() + key.len() + value.len()) as u32);
+        buffer.put_u32(0xcafe);
+        buffer.put_u64(cas);
+        buffer.put_slice(extras);
+        buffer.put_slice(key);
+        buffer.put_slice(value);
+        buffer
+    }
+
+    fn parse(opcode: u8, cas: u64, extras: &[u8], key: &[u8], value: &[u8]) -> Packet {
+        let mut buffer = request_bytes(opcode, cas, extras, key, value);
+        parse_packet(&mut buffer).unwrap().unwrap()
+    }
+
+    fn request(opcode: u8, key: &[u8]) -> Request {
+        parse(opcode, 0, &[], key, &[]).request()
+    }
+
+    fn hit(key: &str) -> Response {
+        Response::Values {
+            values: vec![Value {
+                key: key.to_owned(),
+                flags: 3,
+                cas: 9,
+                data: Bytes::from_static(b"v"),
+                expiry_timestamp: 0,
+                accessed_timestamp: 0,
+                fetched: false,
+            }],
+            with_cas: false,
+        }
+    }
+
+    fn miss() -> Response {
+        Response::Values {
+            values: Vec::new(),
+            with_cas: false,
+        }
+    }
+
+    /// The status, CAS and key of a reply made of a single packet.
+    fn reply(frames: &[Bytes]) -> (u16, u64, Bytes) {
+        assert_eq!(frames.len(), 2);
+        let mut header = frames[0].clone();
+        let key_length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let extras_length = header[4] as usize;
+        let status = u16::from_be_bytes([header[6], header[7]]);
+        let cas = u64::from_be_bytes(header[16..24].try_into().unwrap());
+        header.advance(HEADER_SIZE + extras_length);
+        (status, cas, header.split_to(key_length))
+    }
+
+    #[test]
+    fn partial_packets_wait_for_more_bytes() {
+        let full = request_bytes(SET, 0, &[0; 8], b"key", b"value");
+        for len in [0, 1, HEADER_SIZE - 1, HEADER_SIZE, full.len() - 1] {
+            let mut buffer = BytesMut::from(&full[..len]);
+            assert!(parse_packet(&mut buffer).unwrap().is_none());
+            assert_eq!(buffer.len(), len);
+        }
+        let mut buffer = full.clone();
+        buffer.extend_from_slice(&full[..HEADER_SIZE]);
+        let packet = parse_packet(&mut buffer).unwrap().unwrap();
+        assert_eq!(&packet.key[..], b"key");
+        assert_eq!(&packet.value[..], b"value");
+        assert_eq!(buffer.len(), HEADER_SIZE);
+    }
+
+    #[test]
+    fn quiet_gets_only_reply_on_a_hit() {
+        for opcode in [GETQ, GETKQ] {
+            let request = request(opcode, b"key");
+            assert!(encode_response(&request, &miss()).is_empty());
+            assert_eq!(reply(&encode_response(&request, &hit("key"))).0, STATUS_OK);
+        }
+        let (status, cas, key) = reply(&encode_response(&request(GETKQ, b"key"), &hit("key")));
+        assert_eq!((status, cas, &key[..]), (STATUS_OK, 9, &b"key"[..]));
+    }
+
+    #[test]
+    fn keyed_misses_return_the_key() {
+        let (status, _, key) = reply(&encode_response(&request(GETK, b"key"), &miss()));
+        assert_eq!((status, &key[..]), (STATUS_KEY_NOT_FOUND, &b"key"[..]));
+        let (status, _, key) = reply(&encode_response(&request(GET, b"key"), &miss()));
+        assert_eq!((status, &key[..]), (STATUS_KEY_NOT_FOUND, &b""[..]));
+    }
+
+    #[test]
+    fn sets_with_a_cas_become_cas_commands() {
+        let extras = [0, 0, 0, 3, 0, 0, 0, 0];
+        let ins = parse(SET, 7, &extras, b"key", b"value").into_instruction();
+        assert!(matches!(
+            ins.unwrap(),
+            Instruction::Cas {
+                cas_unique: 7,
+                flags: 3,
+                ..
+            }
+        ));
+        let ins = parse(SET, 0, &extras, b"key", b"value").into_instruction();
+        assert!(matches!(ins.unwrap(), Instruction::Set { flags: 3, .. }));
+    }
+
+    #[test]
+    fn increments_create_counters_unless_told_not_to() {
+        let mut extras = BytesMut::new();
+        extras.put_u64(2);
+        extras.put_u64(10);
+        extras.put_u32(60);
+        let ins = parse(INCREMENT, 0, &extras, b"key", &[]).into_instruction();
+        assert!(matches!(
+            ins.unwrap(),
+            Instruction::Incr {
+                delta: 2,
+                initial: Some((10, 60)),
+                ..
+            }
+        ));
+
+        let mut extras = BytesMut::new();
+        extras.put_u64(2);
+        extras.put_u64(10);
+        extras.put_u32(NO_CREATE);
+        let ins = parse(DECREMENT, 0, &extras, b"key", &[]).into_instruction();
+        assert!(matches!(
+            ins.unwrap(),
+            Instruction::Decr {
+                delta: 2,
+                initial: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn counter_replies_carry_the_new_cas() {
+        let counter = Response::Counter {
+            value: Bytes::from_static(b"42"),
+            cas: 5,
+        };
+        let frames = encode_response(&request(INCREMENT, b"key"), &counter);
+        assert_eq!(reply(&frames).1, 5);
+        assert_eq!(&frames[1][..], &42u64.to_be_bytes());
+        assert!(encode_response(&request(INCREMENTQ, b"key"), &counter).is_empty());
+    }
+}
@@ -1,12 +1,18 @@
-use anyhow::{anyhow, Context, Result};
+use std::sync::{atomic::Ordering, Arc};
+
+use anyhow::{anyhow, Result};
 use log::info;
-use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::{error::CleanupError, Db, LockManager};
+use crate::{
+    error::CleanupError,
+    executor,
+    stats::{self, Stats},
+    Db, MemoryManager,
+};
 
 const CLEAN_RATIO: f32 = 0.10;
 
-pub async fn clean(cache: Db, lock_manager: LockManager) -> Result<()> {
+pub async fn clean(cache: Db, stats: Arc<Stats>, memory: MemoryManager) -> Result<()> {
     /*
      * Look at 10% keys. If 25% of the keys are evictable repear the process.
      * Repeat the process until less than 25% keys sampled are evicted.
@@ -18,13 +24,7 @@ pub async fn clean(cache: Db, lock_manager: LockManager) -> Result<()> {
     let keys = cache.iter();
     let mut keys_to_remove: Vec<String> = Vec::new();
     for item in keys {
-        lock_manager.get(item.key()).unwrap().read();
-        let db_item = item.value();
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .context("TIME ERROR")?
-            .as_millis();
-        if current_time > db_item.expiry_timestamp && db_item.expiry_timestamp != 0 {
+        if executor::is_expired(&cache, item.value()) {
             keys_to_remove.push(item.key().to_string());
             if keys_to_remove.len() == num_clean {
                 break;
@@ -33,13 +33,20 @@ pub async fn clean(cache: Db, lock_manager: LockManager) -> Result<()> {
     }
 
     for key in &keys_to_remove {
-        lock_manager.get(key).unwrap().write();
-        cache.remove(key);
+        let _key_lock = cache.lock(key);
+        // The key may have been stored again since it was found expired
+        if let Some((_, db_item)) =
+            cache.remove_if(key, |_, item| executor::is_expired(&cache, item))
+        {
+            memory.on_remove(key, &db_item);
+            // Items thrown away by flush_all did not expire
+            let flushed = cache.is_flushed(&db_item, executor::current_millis());
+            if !db_item.fetched.load(Ordering::Relaxed) && !flushed {
+                stats::incr(&stats.expired_unfetched);
+            }
+        }
     }
 
-    for key in &keys_to_remove {
-        lock_manager.remove(key);
-    }
     info!("Cleanup Complete. Cleaned {} keys", keys_to_remove.len());
 
     // Determine if function needs to be called again
@@ -1,43 +1,85 @@
-use std::io::Cursor;
+use std::io::IoSlice;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
 use bytes::{Buf, Bytes, BytesMut};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
 
 use crate::{
-    error::{NetError, ParseError},
+    binary,
+    error::{NetError, ParseError, ProtocolError},
     instruction::{self, Instruction},
+    response::Response,
+    slabs,
 };
 
+// Long enough for a get of a few hundred keys of the largest size
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// A client connection over any byte stream, such as a TCP or unix socket.
 #[derive(Debug)]
-pub struct Connection {
-    stream: BufWriter<TcpStream>,
+pub struct Connection<S> {
+    stream: BufWriter<S>,
     buffer: BytesMut,
     waiting_instruction: Option<(Instruction, usize)>,
+    protocol: Option<Protocol>,
+    // The binary request being answered
+    binary_request: Option<binary::Request>,
+    // Bytes of a rejected data block still to be thrown away
+    swallow: usize,
+    // Bytes at the start of the buffer already searched for the end of a line
+    scanned: usize,
+}
+
+/// The protocol of a connection, told apart by its first byte.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    Text,
+    Binary,
 }
 
-impl Connection {
-    pub fn new(socket: TcpStream) -> Connection {
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(socket: S) -> Connection<S> {
         Connection {
             stream: BufWriter::new(socket),
             buffer: BytesMut::new(),
             waiting_instruction: None,
+            protocol: None,
+            binary_request: None,
+            swallow: 0,
+            scanned: 0,
         }
     }
 
+    pub fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
     pub async fn read_instruction(&mut self) -> Result<Instruction> {
+        let protocol = match self.protocol {
+            Some(protocol) => protocol,
+            None => {
+                while self.buffer.is_empty() {
+                    self.read_more().await?;
+                }
+                let protocol = if self.buffer[0] == binary::REQUEST_MAGIC {
+                    Protocol::Binary
+                } else {
+                    Protocol::Text
+                };
+                self.protocol = Some(protocol);
+                protocol
+            }
+        };
+        self.skip_swallowed().await?;
+        if protocol == Protocol::Binary {
+            return self.read_binary_instruction().await;
+        }
         loop {
+            // Commands can be pipelined, so the buffer is parsed before reading more bytes
             match self.waiting_instruction.clone() {
                 Some((ins, data_size)) => {
                     // Waiting for data following a instruction
-                    let n = self.stream.read_buf(&mut self.buffer).await?;
-                    if n == 0 {
-                        anyhow::bail!(NetError::ConnClosedByClient)
-                    }
-                    match self.parse_data(data_size).await {
+                    match self.parse_data(data_size) {
                         Ok(data) => {
                             self.clear_waiting();
                             return Ok(instruction::complete_ins(ins, data));
@@ -45,29 +87,42 @@ impl Connection {
                         Err(err) => match err.downcast_ref() {
                             Some(ParseError::InsufficientData) => {
                                 // Need more bytes
+                                self.read_more().await?;
                                 continue;
                             }
-                            _ => anyhow::bail!(err),
+                            _ => {
+                                self.clear_waiting();
+                                anyhow::bail!(err)
+                            }
                         },
                     }
                 }
                 None => {
                     // Waiting for new instruction
-                    let n = self.stream.read_buf(&mut self.buffer).await?;
-                    if n == 0 {
-                        anyhow::bail!(NetError::ConnClosedByClient)
-                    }
-                    match self.parse_instruction().await {
-                        Ok(ins) => return Ok(ins.clone()),
+                    match self.parse_instruction() {
+                        Ok(ins) => return Ok(ins),
                         Err(e) => match e.downcast_ref() {
+                            Some(ParseError::InsufficientWaiting(_, data_size))
+                                if *data_size > slabs::PAGE_SIZE =>
+                            {
+                                // Like memcached, the block is thrown away as it
+                                // comes in rather than buffered
+                                self.swallow = data_size.saturating_add(2);
+                                anyhow::bail!(ParseError::TooLarge)
+                            }
                             Some(ParseError::InsufficientWaiting(ins, data_size)) => {
                                 self.set_waiting(ins.clone(), *data_size);
                                 continue;
                             }
                             Some(ParseError::InsufficientData) => {
                                 // Need more bytes
+                                self.read_more().await?;
                                 continue;
                             }
+                            Some(ParseError::InvalidKey(swallow)) => {
+                                self.swallow = *swallow;
+                                return Err(e);
+                            }
                             _ => return Err(e),
                         },
                     };
@@ -76,6 +131,62 @@ impl Connection {
         }
     }
 
+    async fn read_binary_instruction(&mut self) -> Result<Instruction> {
+        loop {
+            let packet = match binary::parse_packet(&mut self.buffer) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => {
+                    self.read_more().await?;
+                    continue;
+                }
+                Err(e) => {
+                    if let Some(ParseError::PacketTooLarge(request, body_length)) = e.downcast_ref()
+                    {
+                        // Like in the text protocol, the body is thrown away as it comes in
+                        self.binary_request = Some(request.clone());
+                        self.swallow = *body_length;
+                    }
+                    return Err(e);
+                }
+            };
+            let request = packet.request();
+            self.binary_request = Some(request.clone());
+            if packet.is_noop() {
+                self.write_packets(binary::encode_response(&request, &Response::Ok))
+                    .await?;
+                continue;
+            }
+            let ins = packet.into_instruction()?;
+            if let Instruction::Quit = ins {
+                // The connection is closed without going back to the executor
+                self.write_packets(binary::encode_response(&request, &Response::Ok))
+                    .await?;
+            }
+            return Ok(ins);
+        }
+    }
+
+    /// Throws away what is left of a rejected command's data.
+    async fn skip_swallowed(&mut self) -> Result<()> {
+        while self.swallow > 0 {
+            if self.buffer.is_empty() {
+                self.read_more().await?;
+            }
+            let n = self.swallow.min(self.buffer.len());
+            self.buffer.advance(n);
+            self.swallow -= n;
+        }
+        Ok(())
+    }
+
+    async fn read_more(&mut self) -> Result<()> {
+        let n = self.stream.read_buf(&mut self.buffer).await?;
+        if n == 0 {
+            anyhow::bail!(NetError::ConnClosedByClient)
+        }
+        Ok(())
+    }
+
     pub fn set_waiting(&mut self, ins: Instruction, data_size: usize) {
         self.waiting_instruction = Some((ins, data_size));
     }
@@ -84,70 +195,140 @@ impl Connection {
         self.waiting_instruction = None;
     }
 
-    pub async fn write_line(&mut self, line: String) -> Result<()> {
-        self.stream
-            .write_all(line.as_bytes())
-            .await
-            .context("Failed to write")?;
-        self.stream
-            .write_all(b"\r\n")
-            .await
-            .context("Failed to write")?;
-        self.stream.flush().await.context("Failed to flush")?;
-        Ok(())
+    pub async fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        self.write_frames(&[line, b"\r\n"]).await
     }
 
-    async fn parse_data<'a>(&mut self, data_size: usize) -> Result<Bytes> {
-        let mut buf_cursor = Cursor::new(&self.buffer[..]);
-        let line = get_line(&mut buf_cursor)?; // gets a line till the delimiter \r\n
-        let v = line.to_vec(); // TODO: figure out how to not copy
-        self.buffer.advance(buf_cursor.position() as usize); // advance the buffer to clear current instruciton
-        let data = Bytes::from(v);
+    pub async fn write_response(&mut self, response: &Response) -> Result<()> {
+        match (self.protocol, &self.binary_request) {
+            (Some(Protocol::Binary), Some(request)) => {
+                let packets = binary::encode_response(request, response);
+                self.write_packets(packets).await
+            }
+            _ => self.write_packets(response.text_frames()).await,
+        }
+    }
 
-        if data.len() != data_size {
-            anyhow::bail!(ParseError::InvalidData)
+    pub async fn write_error(&mut self, error: &ProtocolError) -> Result<()> {
+        match (self.protocol, &self.binary_request) {
+            (Some(Protocol::Binary), Some(request)) => {
+                let packets = binary::encode_error(request, error);
+                self.write_packets(packets).await
+            }
+            _ => self.write_line(error.to_string().as_bytes()).await,
         }
+    }
 
-        Ok(data)
+    async fn write_packets(&mut self, frames: Vec<Bytes>) -> Result<()> {
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let frames: Vec<&[u8]> = frames.iter().map(|frame| &frame[..]).collect();
+        self.write_frames(&frames).await
     }
 
-    async fn parse_instruction(&mut self) -> Result<Instruction> {
-        let mut buf_cursor = Cursor::new(&self.buffer[..]);
-        let line = get_line(&mut buf_cursor)?; // gets a line till the delimiter \r\n
-        let line = String::from_utf8(line.to_vec());
-        let mut data: Option<Bytes> = None;
+    async fn write_frames(&mut self, frames: &[&[u8]]) -> Result<()> {
+        // Vectored writes let large values go out without being copied into one buffer
+        let mut slices: Vec<IoSlice> = frames.iter().map(|frame| IoSlice::new(frame)).collect();
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            let n = self
+                .stream
+                .write_vectored(slices)
+                .await
+                .context("Failed to write")?;
+            if n == 0 {
+                anyhow::bail!("Failed to write")
+            }
+            IoSlice::advance_slices(&mut slices, n);
+        }
+        self.stream.flush().await.context("Failed to flush")?;
+        Ok(())
+    }
 
-        if let Ok(new_line) = get_line(&mut buf_cursor) {
-            // Sometimes instructions like "set" are 2 lines
-            let v = new_line.to_vec(); // TODO: figure out how to not copy
-            data = Some(Bytes::from(v));
+    fn parse_data(&mut self, data_size: usize) -> Result<Bytes> {
+        // The data block is exactly data_size bytes followed by \r\n, and may itself contain \r\n
+        if self.buffer.len() < data_size + 2 {
+            anyhow::bail!(ParseError::InsufficientData)
+        }
+        if &self.buffer[data_size..data_size + 2] != b"\r\n" {
+            // Drop the bad block along with the rest of its line
+            self.buffer.advance(data_size);
+            match find_line_end(&self.buffer, 0) {
+                Some(end) => self.buffer.advance(end + 2),
+                None => self.buffer.clear(),
+            }
+            anyhow::bail!(ParseError::InvalidData)
         }
 
-        self.buffer.advance(buf_cursor.position() as usize); // advance the buffer to clear current instruciton
+        let data = self.buffer.split_to(data_size).freeze();
+        self.buffer.advance(2);
+        Ok(data)
+    }
 
-        if let Ok(line) = line {
-            if let Some(data) = data {
-                return Ok(instruction::parse_ins_with_data(line, data)?);
-            } else {
-                let instruction = instruction::parse_string(line)?;
-                Ok(instruction)
+    fn parse_instruction(&mut self) -> Result<Instruction> {
+        let end = match find_line_end(&self.buffer, self.scanned) {
+            Some(end) if end <= MAX_LINE_LENGTH => end,
+            None if self.buffer.len() <= MAX_LINE_LENGTH => {
+                // Only the bytes still to come are searched next time
+                self.scanned = self.buffer.len();
+                anyhow::bail!(ParseError::InsufficientData)
             }
-        } else {
-            anyhow::bail!(ParseError::InvalidInstruction)
+            _ => anyhow::bail!(ParseError::LineTooLong),
+        };
+        self.scanned = 0;
+        let line = String::from_utf8(self.buffer.split_to(end).to_vec());
+        self.buffer.advance(2);
+
+        match line {
+            Ok(line) => instruction::parse_string(line),
+            Err(_) => anyhow::bail!(ParseError::InvalidInstruction),
         }
     }
 }
 
-fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8]> {
-    let start = src.position() as usize;
-    let end = src.get_ref().len() - 1;
+/// The position of the \r\n ending the first line of the buffer, searching
+/// from `from` on.
+fn find_line_end(buffer: &[u8], from: usize) -> Option<usize> {
+    // The \r may be the last byte searched before
+    let start = from.saturating_sub(1);
+    buffer
+        .get(start..)?
+        .windows(2)
+        .position(|pair| pair == b"\r\n")
+        .map(|i| start + i)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
 
-    for i in start..end {
-        if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
-            src.set_position((i + 2) as u64);
+    use super::*;
 
-            return Ok(&src.get_ref()[start..i]);
+    fn connect() -> (Connection<DuplexStream>, DuplexStream) {
+        let (server, client) = duplex(4 * MAX_LINE_LENGTH);
+        (Connection::new(server), client)
+    }
+
+    #[tokio::test]
+    async fn lines_are_read_across_packets() {
+        let (mut connection, mut client) = connect();
+        let reader = tokio::spawn(async move { connection.read_instruction().await });
+        for part in [&b"get ke"[..], b"y\r", b"\n"] {
+            client.write_all(part).await.unwrap();
+            client.flush().await.unwrap();
+            tokio::task::yield_now().await;
         }
+        let ins = reader.await.unwrap().unwrap();
+        assert!(matches!(ins, Instruction::Get { keys } if keys == ["key"]));
+    }
+
+    #[tokio::test]
+    async fn overlong_lines_are_rejected() {
+        let (mut connection, mut client) = connect();
+        let line = format!("get {}", "k ".repeat(MAX_LINE_LENGTH));
+        client.write_all(line.as_bytes()).await.unwrap();
+        let err = connection.read_instruction().await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(ParseError::LineTooLong)));
     }
-    Err(anyhow!(ParseError::InsufficientData))
 }
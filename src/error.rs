@@ -1,13 +1,20 @@
 use std::fmt::{self, Debug, Display, Formatter};
 
-use crate::instruction::Instruction;
+use crate::{binary::Request, instruction::Instruction};
 
 #[derive(Debug, Clone)]
 pub enum ParseError {
     InsufficientData,
     InsufficientWaiting(Instruction, usize),
+    UnknownCommand,
     InvalidInstruction,
     InvalidData,
+    TooLarge,
+    LineTooLong,
+    // Bytes of the command's data block to throw away
+    InvalidKey(usize),
+    // A binary request whose body of the given length is thrown away
+    PacketTooLarge(Request, usize),
 }
 
 impl Display for ParseError {
@@ -15,8 +22,69 @@ impl Display for ParseError {
         match self {
             ParseError::InsufficientData => write!(f, "INSUFFICIENT DATA"),
             ParseError::InsufficientWaiting(_, _) => write!(f, "WAITING FOR DATA"),
+            ParseError::UnknownCommand => write!(f, "UNKNOWN COMMAND"),
             ParseError::InvalidInstruction => write!(f, "INVALID INSTRUCION"),
             ParseError::InvalidData => write!(f, "INVALID DATA"),
+            ParseError::TooLarge => write!(f, "TOO LARGE"),
+            ParseError::LineTooLong => write!(f, "LINE TOO LONG"),
+            ParseError::InvalidKey(_) => write!(f, "INVALID KEY"),
+            ParseError::PacketTooLarge(_, _) => write!(f, "PACKET TOO LARGE"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The error replies of the text protocol.
+#[derive(Debug, Clone)]
+pub enum ProtocolError {
+    /// The command is not known, replied to with `ERROR`.
+    UnknownCommand,
+    /// The command or its data is malformed, replied to with `CLIENT_ERROR <msg>`.
+    ClientError(String),
+    /// The server failed to carry out the command, replied to with `SERVER_ERROR <msg>`.
+    ServerError(String),
+    /// An increment or decrement of a value that is not a number.
+    NonNumeric,
+    /// A value bigger than the largest slab chunk.
+    TooLarge,
+    /// No chunk could be freed for a value.
+    OutOfMemory,
+}
+
+impl ProtocolError {
+    /// Picks the reply for an error raised while reading or executing a command.
+    pub fn from_error(err: &anyhow::Error) -> ProtocolError {
+        if let Some(err) = err.downcast_ref::<ProtocolError>() {
+            return err.clone();
+        }
+        match err.downcast_ref::<ParseError>() {
+            Some(ParseError::UnknownCommand) => ProtocolError::UnknownCommand,
+            Some(ParseError::InvalidData) => {
+                ProtocolError::ClientError("bad data chunk".to_owned())
+            }
+            Some(ParseError::TooLarge | ParseError::PacketTooLarge(_, _)) => {
+                ProtocolError::TooLarge
+            }
+            Some(ParseError::LineTooLong) => ProtocolError::ClientError("line too long".to_owned()),
+            Some(_) => ProtocolError::ClientError("bad command line format".to_owned()),
+            None => ProtocolError::ServerError(err.to_string()),
+        }
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownCommand => write!(f, "ERROR"),
+            ProtocolError::ClientError(msg) => write!(f, "CLIENT_ERROR {}", msg),
+            ProtocolError::ServerError(msg) => write!(f, "SERVER_ERROR {}", msg),
+            ProtocolError::NonNumeric => write!(
+                f,
+                "CLIENT_ERROR cannot increment or decrement non-numeric value"
+            ),
+            ProtocolError::TooLarge => write!(f, "SERVER_ERROR object too large for cache"),
+            ProtocolError::OutOfMemory => write!(f, "SERVER_ERROR out of memory storing object"),
         }
     }
 }
@@ -24,12 +92,16 @@ impl Display for ParseError {
 #[derive(Debug, Clone)]
 pub enum NetError {
     ConnClosedByClient,
+    ShutdownRequested,
+    InvalidPacket,
 }
 
 impl Display for NetError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             NetError::ConnClosedByClient => write!(f, "CONNECTION CLOSED BY CLIENT"),
+            NetError::ShutdownRequested => write!(f, "SHUTDOWN REQUESTED"),
+            NetError::InvalidPacket => write!(f, "INVALID PACKET"),
         }
     }
 }
@@ -0,0 +1,624 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use clap::ValueEnum;
+
+use crate::{slabs::Slabs, DBItem};
+
+// Rough per-item bookkeeping cost on top of the key and value bytes
+const ITEM_OVERHEAD: usize = mem::size_of::<DBItem>() + mem::size_of::<String>();
+// Number of keys looked at by the random sampling policy for each eviction
+const SAMPLE_SIZE: usize = 5;
+// Share of the W-TinyLFU entries kept in the admission window, in percent
+const WINDOW_PERCENT: usize = 1;
+// Share of the W-TinyLFU main space kept in the protected segment, in percent
+const PROTECTED_PERCENT: usize = 80;
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 1 << 16;
+
+/// Decides which item goes when the cache is over its memory limit.
+///
+/// The executor reports every insert, access and removal of a key. When the
+/// cache needs room it asks for a victim, which the policy forgets about.
+/// A panic while a policy is locked leaves at worst a key out of order, so
+/// the policies keep going with a poisoned lock.
+pub trait EvictionPolicy: Debug + Send + Sync {
+    fn on_insert(&self, key: &str);
+    fn on_access(&self, key: &str);
+    fn on_remove(&self, key: &str);
+    fn victim(&self) -> Option<String>;
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum PolicyKind {
+    /// Evict the least recently used item
+    Lru,
+    /// Evict the least frequently used item, oldest first on ties
+    Lfu,
+    /// Window TinyLFU: a small LRU window in front of a segmented LRU,
+    /// with a count-min sketch deciding which of the two gives up an item
+    #[value(name = "tinylfu")]
+    TinyLfu,
+    /// Evict the least recently used of a few randomly sampled items
+    Random,
+}
+
+impl PolicyKind {
+    /// One policy for each of the slab classes. The TinyLFU policies share a
+    /// single frequency sketch, how often a key is used has nothing to do
+    /// with its class.
+    pub fn build(self, classes: usize) -> Vec<Box<dyn EvictionPolicy>> {
+        let sketch = Arc::new(Mutex::new(CountMinSketch::default()));
+        (0..classes)
+            .map(|_| -> Box<dyn EvictionPolicy> {
+                match self {
+                    PolicyKind::Lru => Box::new(Lru::default()),
+                    PolicyKind::Lfu => Box::new(Lfu::default()),
+                    PolicyKind::TinyLfu => Box::new(TinyLfu::new(sketch.clone())),
+                    PolicyKind::Random => Box::new(RandomSampling::default()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Tracks the bytes used by the cache, stores values in the slabs and keeps
+/// one eviction policy per slab class.
+#[derive(Debug)]
+pub struct Memory {
+    limit: u64,
+    used: AtomicU64,
+    slabs: Slabs,
+    policies: Vec<Box<dyn EvictionPolicy>>,
+}
+
+impl Memory {
+    /// A limit of 0 means the cache may grow without bound.
+    pub fn new(limit: u64, growth_factor: f64, policy: PolicyKind) -> Memory {
+        let slabs = Slabs::new(limit, growth_factor);
+        let policies = policy.build(slabs.num_classes());
+        Memory {
+            limit,
+            used: AtomicU64::new(0),
+            slabs,
+            policies,
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn slabs(&self) -> &Slabs {
+        &self.slabs
+    }
+
+    /// The slab class for an item, None if it is too large to store. Like
+    /// in memcached the chunk makes room for the key and the item header
+    /// too, so the limit holds for everything the item takes up.
+    pub fn class_for(&self, key: &str, value_len: usize) -> Option<usize> {
+        self.slabs.class_for(key.len() + value_len + ITEM_OVERHEAD)
+    }
+
+    /// Copies a value made of the given parts into a chunk of the class.
+    pub fn store(&self, class: usize, parts: &[&[u8]]) -> Option<Bytes> {
+        self.slabs.alloc(class, parts)
+    }
+
+    pub fn on_insert(&self, key: &str, item: &DBItem) {
+        self.used.fetch_add(item_size(key, item), Ordering::Relaxed);
+        self.policy(key, item).on_insert(key);
+    }
+
+    pub fn on_access(&self, key: &str, item: &DBItem) {
+        self.policy(key, item).on_access(key);
+    }
+
+    pub fn on_remove(&self, key: &str, item: &DBItem) {
+        self.used.fetch_sub(item_size(key, item), Ordering::Relaxed);
+        self.policy(key, item).on_remove(key);
+    }
+
+    /// Picks the next key to evict from the class and forgets about it.
+    pub fn victim(&self, class: usize) -> Option<String> {
+        self.policies[class].victim()
+    }
+
+    fn policy(&self, key: &str, item: &DBItem) -> &dyn EvictionPolicy {
+        // Every stored item fits in a class, it was allocated from one
+        let class = self.class_for(key, item.value.len()).unwrap_or(0);
+        self.policies[class].as_ref()
+    }
+}
+
+fn item_size(key: &str, item: &DBItem) -> u64 {
+    (key.len() + item.value.len() + ITEM_OVERHEAD) as u64
+}
+
+/// Keys ordered by when they were last touched, oldest first.
+#[derive(Debug, Default)]
+struct LruList {
+    clock: u64,
+    order: BTreeMap<u64, String>,
+    last_used: HashMap<String, u64>,
+}
+
+impl LruList {
+    fn len(&self) -> usize {
+        self.last_used.len()
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.last_used.contains_key(key)
+    }
+
+    /// Moves the key to the most recently used end, adding it if needed.
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(previous) = self.last_used.insert(key.to_owned(), clock) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(clock, key.to_owned());
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.last_used.remove(key) {
+            Some(previous) => {
+                self.order.remove(&previous);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn oldest(&self) -> Option<&String> {
+        self.order.first_key_value().map(|(_, key)| key)
+    }
+
+    fn pop_oldest(&mut self) -> Option<String> {
+        let (_, key) = self.order.pop_first()?;
+        self.last_used.remove(&key);
+        Some(key)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Lru {
+    list: Mutex<LruList>,
+}
+
+impl EvictionPolicy for Lru {
+    fn on_insert(&self, key: &str) {
+        self.list
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .touch(key);
+    }
+
+    fn on_access(&self, key: &str) {
+        self.list
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .touch(key);
+    }
+
+    fn on_remove(&self, key: &str) {
+        self.list
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(key);
+    }
+
+    fn victim(&self) -> Option<String> {
+        self.list
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop_oldest()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Lfu {
+    state: Mutex<LfuState>,
+}
+
+#[derive(Debug, Default)]
+struct LfuState {
+    clock: u64,
+    // (uses, clock of the last use) -> key, least used first
+    order: BTreeMap<(u64, u64), String>,
+    entries: HashMap<String, (u64, u64)>,
+}
+
+impl LfuState {
+    fn bump(&mut self, key: &str, reset: bool) {
+        self.clock += 1;
+        let uses = match self.entries.get(key) {
+            Some(previous) => {
+                self.order.remove(previous);
+                if reset {
+                    1
+                } else {
+                    previous.0 + 1
+                }
+            }
+            None => 1,
+        };
+        let entry = (uses, self.clock);
+        self.entries.insert(key.to_owned(), entry);
+        self.order.insert(entry, key.to_owned());
+    }
+}
+
+impl EvictionPolicy for Lfu {
+    fn on_insert(&self, key: &str) {
+        // A new value starts counting its uses again
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .bump(key, true);
+    }
+
+    fn on_access(&self, key: &str) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .bump(key, false);
+    }
+
+    fn on_remove(&self, key: &str) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(previous) = state.entries.remove(key) {
+            state.order.remove(&previous);
+        }
+    }
+
+    fn victim(&self) -> Option<String> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let (_, key) = state.order.pop_first()?;
+        state.entries.remove(&key);
+        Some(key)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RandomSampling {
+    state: Mutex<RandomState>,
+}
+
+#[derive(Debug, Default)]
+struct RandomState {
+    clock: u64,
+    rng: u64,
+    keys: Vec<String>,
+    // key -> (position in keys, clock of the last use)
+    entries: HashMap<String, (usize, u64)>,
+}
+
+impl RandomState {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.entries.get_mut(key) {
+            Some(entry) => entry.1 = clock,
+            None => {
+                self.entries
+                    .insert(key.to_owned(), (self.keys.len(), clock));
+                self.keys.push(key.to_owned());
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((position, _)) = self.entries.remove(key) {
+            self.keys.swap_remove(position);
+            if let Some(moved) = self.keys.get(position) {
+                self.entries.get_mut(moved).unwrap().0 = position;
+            }
+        }
+    }
+
+    // xorshift64, good enough to pick samples
+    fn next_random(&mut self) -> u64 {
+        if self.rng == 0 {
+            self.rng = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_nanos() as u64)
+                .unwrap_or(0)
+                | 1;
+        }
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+impl EvictionPolicy for RandomSampling {
+    fn on_insert(&self, key: &str) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .touch(key);
+    }
+
+    fn on_access(&self, key: &str) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .touch(key);
+    }
+
+    fn on_remove(&self, key: &str) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(key);
+    }
+
+    fn victim(&self) -> Option<String> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.keys.is_empty() {
+            return None;
+        }
+        let mut victim: Option<(u64, String)> = None;
+        for _ in 0..SAMPLE_SIZE {
+            let position = (state.next_random() % state.keys.len() as u64) as usize;
+            let key = &state.keys[position];
+            let last_used = state.entries[key].1;
+            if victim
+                .as_ref()
+                .is_none_or(|(oldest, _)| last_used < *oldest)
+            {
+                victim = Some((last_used, key.clone()));
+            }
+        }
+        let (_, key) = victim?;
+        state.remove(&key);
+        Some(key)
+    }
+}
+
+/// Approximate access counts in a fixed amount of memory, allocated on the
+/// first count. Counters are halved every so often so that old popularity
+/// fades away.
+#[derive(Debug, Default)]
+struct CountMinSketch {
+    counters: Vec<u8>,
+    additions: usize,
+}
+
+impl CountMinSketch {
+    fn slots(key: &str) -> [usize; SKETCH_DEPTH] {
+        let mut slots = [0; SKETCH_DEPTH];
+        for (row, slot) in slots.iter_mut().enumerate() {
+            let mut hasher = DefaultHasher::new();
+            row.hash(&mut hasher);
+            key.hash(&mut hasher);
+            *slot = row * SKETCH_WIDTH + (hasher.finish() as usize & (SKETCH_WIDTH - 1));
+        }
+        slots
+    }
+
+    fn increment(&mut self, key: &str) {
+        if self.counters.is_empty() {
+            self.counters = vec![0; SKETCH_DEPTH * SKETCH_WIDTH];
+        }
+        for slot in CountMinSketch::slots(key) {
+            // 4 bit counters are plenty to tell hot keys from cold ones
+            if self.counters[slot] < 15 {
+                self.counters[slot] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= 10 * SKETCH_WIDTH {
+            for counter in self.counters.iter_mut() {
+                *counter /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency(&self, key: &str) -> u8 {
+        CountMinSketch::slots(key)
+            .iter()
+            .map(|slot| self.counters.get(*slot).copied().unwrap_or(0))
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub struct TinyLfu {
+    state: Mutex<TinyLfuState>,
+    // Shared by the policies of every class, locked after the state
+    sketch: Arc<Mutex<CountMinSketch>>,
+}
+
+#[derive(Debug, Default)]
+struct TinyLfuState {
+    window: LruList,
+    probation: LruList,
+    protected: LruList,
+}
+
+impl TinyLfu {
+    fn new(sketch: Arc<Mutex<CountMinSketch>>) -> TinyLfu {
+        TinyLfu {
+            state: Mutex::default(),
+            sketch,
+        }
+    }
+}
+
+impl TinyLfuState {
+    fn len(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+
+    /// Moves window overflow to probation and protected overflow back to probation.
+    fn rebalance(&mut self) {
+        let window_max = (self.len() * WINDOW_PERCENT / 100).max(1);
+        while self.window.len() > window_max {
+            let key = self.window.pop_oldest().unwrap();
+            self.probation.touch(&key);
+        }
+        let main_len = self.probation.len() + self.protected.len();
+        let protected_max = main_len * PROTECTED_PERCENT / 100;
+        while self.protected.len() > protected_max {
+            let key = self.protected.pop_oldest().unwrap();
+            self.probation.touch(&key);
+        }
+    }
+}
+
+impl EvictionPolicy for TinyLfu {
+    fn on_insert(&self, key: &str) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        self.sketch
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .increment(key);
+        if !state.probation.contains(key) && !state.protected.contains(key) {
+            state.window.touch(key);
+            state.rebalance();
+        }
+    }
+
+    fn on_access(&self, key: &str) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        self.sketch
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .increment(key);
+        if state.window.contains(key) {
+            state.window.touch(key);
+        } else if state.probation.remove(key) || state.protected.contains(key) {
+            state.protected.touch(key);
+            state.rebalance();
+        }
+    }
+
+    fn on_remove(&self, key: &str) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if !state.window.remove(key) && !state.probation.remove(key) {
+            state.protected.remove(key);
+        }
+    }
+
+    fn victim(&self) -> Option<String> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let candidate = state.window.oldest().cloned();
+        let main_victim = state
+            .probation
+            .oldest()
+            .or_else(|| state.protected.oldest())
+            .cloned();
+        match (candidate, main_victim) {
+            (Some(candidate), Some(main_victim)) => {
+                // The window's oldest item is only admitted to the main space
+                // if it is used more often than the item it would push out
+                let sketch = self.sketch.lock().unwrap_or_else(PoisonError::into_inner);
+                let admit = sketch.frequency(&candidate) > sketch.frequency(&main_victim);
+                drop(sketch);
+                if admit {
+                    state.window.remove(&candidate);
+                    state.probation.touch(&candidate);
+                    if !state.probation.remove(&main_victim) {
+                        state.protected.remove(&main_victim);
+                    }
+                    Some(main_victim)
+                } else {
+                    state.window.remove(&candidate);
+                    Some(candidate)
+                }
+            }
+            (Some(candidate), None) => {
+                state.window.remove(&candidate);
+                Some(candidate)
+            }
+            (None, Some(main_victim)) => {
+                if !state.probation.remove(&main_victim) {
+                    state.protected.remove(&main_victim);
+                }
+                Some(main_victim)
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn victims(policy: &dyn EvictionPolicy) -> Vec<String> {
+        std::iter::from_fn(|| policy.victim()).collect()
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used() {
+        let lru = Lru::default();
+        for key in ["a", "b", "c"] {
+            lru.on_insert(key);
+        }
+        lru.on_access("a");
+        lru.on_remove("c");
+        assert_eq!(victims(&lru), ["b", "a"]);
+    }
+
+    #[test]
+    fn lfu_breaks_ties_by_age() {
+        let lfu = Lfu::default();
+        for key in ["a", "b", "c", "d"] {
+            lfu.on_insert(key);
+        }
+        lfu.on_access("a");
+        lfu.on_access("a");
+        lfu.on_access("c");
+        // Storing a new value starts its count again
+        lfu.on_insert("a");
+        assert_eq!(victims(&lfu), ["b", "d", "a", "c"]);
+    }
+
+    #[test]
+    fn tinylfu_only_admits_window_items_used_more() {
+        let tinylfu = TinyLfu::new(Arc::default());
+        tinylfu.on_insert("cold");
+        tinylfu.on_insert("new");
+        // The window keeps one item, "cold" is in probation
+        assert_eq!(victims(&tinylfu), ["new", "cold"]);
+
+        for key in ["cold", "colder", "hot"] {
+            tinylfu.on_insert(key);
+        }
+        tinylfu.on_access("hot");
+        tinylfu.on_access("hot");
+        assert_eq!(victims(&tinylfu), ["cold", "colder", "hot"]);
+    }
+
+    #[test]
+    fn random_sampling_picks_from_the_keys_left() {
+        let random = RandomSampling::default();
+        assert_eq!(random.victim(), None);
+        random.on_insert("a");
+        random.on_insert("b");
+        random.on_remove("a");
+        assert_eq!(victims(&random), ["b"]);
+    }
+}
@@ -1,239 +1,1246 @@
 use std::{
-    sync::RwLock,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{anyhow, Context, Result};
-use bytes::{BufMut, Bytes, BytesMut};
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use dashmap::mapref::entry::Entry;
+use log::LevelFilter;
 
-use crate::{instruction::Instruction, DBItem, Db, LockManager};
+use crate::{
+    error::{NetError, ProtocolError},
+    eviction::Memory,
+    instruction::{self, Instruction, MetaFlags},
+    response::{Response, Value},
+    stats::{self, Stats},
+    store::Store,
+    DBItem, Db, MemoryManager,
+};
+
+// Exptimes above 30 days are absolute Unix timestamps
+const REALTIME_MAXDELTA: i64 = 60 * 60 * 24 * 30;
+
+pub fn execute(
+    ins: Instruction,
+    cache: Db,
+    stats: Arc<Stats>,
+    memory: MemoryManager,
+) -> Result<Response> {
+    match ins {
+        Instruction::MetaGet { .. }
+        | Instruction::MetaSet { .. }
+        | Instruction::MetaDelete { .. }
+        | Instruction::MetaArithmetic { .. }
+        | Instruction::MetaDebug { .. }
+        | Instruction::MetaNoop => return execute_meta(ins, cache, stats, memory),
+        _ => (),
+    }
+    let mut is_storage = false;
+    // Hit and miss counters for the commands whose outcome is tracked
+    let mut outcome_counters: Option<(&AtomicU64, &AtomicU64)> = None;
+    match &ins {
+        Instruction::Set { .. }
+        | Instruction::Add { .. }
+        | Instruction::Replace { .. }
+        | Instruction::Append { .. }
+        | Instruction::Prepend { .. } => {
+            stats::incr(&stats.cmd_set);
+            is_storage = true;
+        }
+        Instruction::Cas { .. } => {
+            stats::incr(&stats.cmd_set);
+            is_storage = true;
+            outcome_counters = Some((&stats.cas_hits, &stats.cas_misses));
+        }
+        Instruction::Get { keys } | Instruction::Gets { keys } => {
+            stats
+                .cmd_get
+                .fetch_add(keys.len() as u64, Ordering::Relaxed);
+        }
+        Instruction::Gat { keys, .. } | Instruction::Gats { keys, .. } => {
+            stats
+                .cmd_get
+                .fetch_add(keys.len() as u64, Ordering::Relaxed);
+            stats
+                .cmd_touch
+                .fetch_add(keys.len() as u64, Ordering::Relaxed);
+        }
+        Instruction::Touch { .. } => {
+            stats::incr(&stats.cmd_touch);
+            outcome_counters = Some((&stats.touch_hits, &stats.touch_misses));
+        }
+        Instruction::FlushAll { .. } => stats::incr(&stats.cmd_flush),
+        Instruction::Delete { .. } => {
+            outcome_counters = Some((&stats.delete_hits, &stats.delete_misses))
+        }
+        Instruction::Incr { .. } => outcome_counters = Some((&stats.incr_hits, &stats.incr_misses)),
+        Instruction::Decr { .. } => outcome_counters = Some((&stats.decr_hits, &stats.decr_misses)),
+        Instruction::Stats { .. }
+        | Instruction::Version
+        | Instruction::Verbosity { .. }
+        | Instruction::Quit
+        | Instruction::Shutdown => (),
+        // Counted as the commands they are run as
+        Instruction::MetaGet { .. }
+        | Instruction::MetaSet { .. }
+        | Instruction::MetaDelete { .. }
+        | Instruction::MetaArithmetic { .. }
+        | Instruction::MetaDebug { .. }
+        | Instruction::MetaNoop => (),
+    };
 
-pub fn execute(ins: Instruction, cache: Db, lock_manager: LockManager) -> Result<String> {
+    let res = run(ins, cache, &stats, &memory);
+
+    match &res {
+        Ok(Response::NotFound) => {
+            if let Some((_, misses)) = outcome_counters {
+                stats::incr(misses);
+            }
+        }
+        Ok(Response::Exists) => stats::incr(&stats.cas_badval),
+        Ok(Response::NotStored) | Err(_) => (),
+        Ok(_) => {
+            if is_storage {
+                stats::incr(&stats.total_items);
+            }
+            if let Some((hits, _)) = outcome_counters {
+                stats::incr(hits);
+            }
+        }
+    };
+    res
+}
+
+fn run(ins: Instruction, cache: Db, stats: &Stats, memory: &Memory) -> Result<Response> {
+    // Commands that change an item hold its lock until they are done
+    let _key_lock = locked_key(&ins).map(|key| cache.lock(key));
     let mut key_to_delete: Option<String> = None;
-    let mut key_delete_msg: Option<String> = None;
-    let res: Result<String> = match ins {
+    let res: Result<Response> = match ins {
         Instruction::Set {
             key,
+            flags,
             expiry,
             data_size: _,
             data,
-        } => {
-            let mut expiry_milis = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .context("TIME ERROR")?
-                .as_millis()
-                + expiry * 1000;
-            if expiry == 0 {
-                expiry_milis = 0;
-            }
-            cache.insert(
-                key.clone(),
-                DBItem {
-                    expiry_secs: expiry,
-                    expiry_timestamp: expiry_milis,
-                    value: data,
-                },
-            );
-            lock_manager.insert(key, RwLock::new(true));
-            Ok("STORED".to_owned())
-        }
-        Instruction::Get { key } => match cache.get(&key) {
-            Some(val) => {
-                let db_item = val.value();
-                lock_manager.get(&key).unwrap().read();
-                if is_expired(&db_item) {
-                    // Removing the key directly here can cause a deadlock
-                    key_to_delete = Some(key.clone());
-                    key_delete_msg = Some("END".to_owned());
-                }
-
-                let result = match String::from_utf8(db_item.value.to_vec()) {
-                    Ok(value) => format!(
-                        "VALUE {} {} {} \n\r{} \n\rEND",
-                        key,
-                        db_item.expiry_secs,
-                        db_item.value.len(),
-                        value
-                    ),
-                    Err(_) => {
-                        format!(
-                            "VALUE {} {} {} \n\r[object] \n\rEND",
-                            key,
-                            db_item.expiry_secs,
-                            db_item.value.len()
-                        )
-                    }
-                };
-                Ok(result)
-            }
-            None => anyhow::bail!("END"),
-        },
+            noreply: _,
+        } => insert_key(key, flags, expiry, data, &cache, stats, memory),
+        Instruction::Get { keys } => get_values(keys, false, None, cache.clone(), stats, memory),
+        Instruction::Gets { keys } => get_values(keys, true, None, cache.clone(), stats, memory),
+        Instruction::Gat { expiry, keys } => {
+            get_values(keys, false, Some(expiry), cache.clone(), stats, memory)
+        }
+        Instruction::Gats { expiry, keys } => {
+            get_values(keys, true, Some(expiry), cache.clone(), stats, memory)
+        }
         Instruction::Append {
             key,
+            flags: _,
             expiry: _,
             data_size: _,
             data,
+            noreply: _,
         } => {
-            if !lock_manager.contains_key(&key) {
-                anyhow::bail!("NOT_STORED");
-            }
-            lock_manager.get(&key).unwrap().write();
-            let mut value_to_insert: Option<DBItem> = None;
+            let mut existing: Option<(u32, i64, u128, Bytes)> = None;
             match cache.get(&key) {
                 Some(val) => {
                     let db_item = val.value();
-                    if is_expired(&db_item) {
+                    if is_expired(&cache, db_item) {
                         // Removing the key directly here can cause a deadlock
                         key_to_delete = Some(key.clone());
-                        key_delete_msg = Some("NOT_STORED".to_owned());
                     } else {
-                        let mut result = BytesMut::new();
-                        result.put(db_item.value.clone());
-                        result.put(data);
-
-                        value_to_insert = Some(DBItem {
-                            expiry_secs: db_item.expiry_secs,
-                            expiry_timestamp: db_item.expiry_timestamp,
-                            value: Bytes::from(result.to_vec()),
-                        });
+                        existing = Some((
+                            db_item.flags,
+                            db_item.expiry_secs,
+                            db_item.expiry_timestamp,
+                            db_item.value.clone(),
+                        ));
                     }
                 }
-                None => anyhow::bail!("NOT_STORED"),
+                None => return Ok(Response::NotStored),
             }
-            if let Some(val) = value_to_insert {
-                cache.insert(key, val);
-                Ok("STORED".to_owned())
+            if let Some((flags, expiry_secs, expiry_timestamp, value)) = existing {
+                // The new value is written straight into its chunk
+                let value = allocate(&key, &[&value, &data], &cache, stats, memory)?;
+                let db_item = DBItem {
+                    flags,
+                    expiry_secs,
+                    expiry_timestamp,
+                    cas: cache.next_cas(),
+                    stored_timestamp: current_millis(),
+                    accessed_timestamp: AtomicU64::new(current_millis() as u64),
+                    fetched: AtomicBool::new(false),
+                    stale: false,
+                    win_token_sent: AtomicBool::new(false),
+                    value,
+                };
+                store_item(key, db_item, &cache, memory)
             } else {
-                Err(anyhow!("NOT_STORED"))
+                Ok(Response::NotStored)
             }
         }
         Instruction::Prepend {
             key,
+            flags: _,
             expiry: _,
             data_size: _,
             data,
+            noreply: _,
         } => {
-            if !lock_manager.contains_key(&key) {
-                anyhow::bail!("NOT_STORED");
-            }
-            lock_manager.get(&key).unwrap().write();
-            let mut value_to_insert: Option<DBItem> = None;
+            let mut existing: Option<(u32, i64, u128, Bytes)> = None;
             match cache.get(&key) {
                 Some(val) => {
                     let db_item = val.value();
-                    if is_expired(&db_item) {
+                    if is_expired(&cache, db_item) {
                         // Removing the key directly here can cause a deadlock
                         key_to_delete = Some(key.clone());
-                        key_delete_msg = Some("NOT_STORED".to_owned());
                     } else {
-                        let mut result = BytesMut::new();
-                        result.put(data);
-                        result.put(db_item.value.clone());
-
-                        value_to_insert = Some(DBItem {
-                            expiry_secs: db_item.expiry_secs,
-                            expiry_timestamp: db_item.expiry_timestamp,
-                            value: Bytes::from(result.to_vec()),
-                        });
+                        existing = Some((
+                            db_item.flags,
+                            db_item.expiry_secs,
+                            db_item.expiry_timestamp,
+                            db_item.value.clone(),
+                        ));
                     }
                 }
-                None => anyhow::bail!("NOT_STORED"),
+                None => return Ok(Response::NotStored),
             }
-            if let Some(val) = value_to_insert {
-                cache.insert(key, val);
-                Ok("STORED".to_owned())
+            if let Some((flags, expiry_secs, expiry_timestamp, value)) = existing {
+                // The new value is written straight into its chunk
+                let value = allocate(&key, &[&data, &value], &cache, stats, memory)?;
+                let db_item = DBItem {
+                    flags,
+                    expiry_secs,
+                    expiry_timestamp,
+                    cas: cache.next_cas(),
+                    stored_timestamp: current_millis(),
+                    accessed_timestamp: AtomicU64::new(current_millis() as u64),
+                    fetched: AtomicBool::new(false),
+                    stale: false,
+                    win_token_sent: AtomicBool::new(false),
+                    value,
+                };
+                store_item(key, db_item, &cache, memory)
             } else {
-                Err(anyhow!("NOT_STORED"))
+                Ok(Response::NotStored)
             }
         }
         Instruction::Add {
             key,
+            flags,
             expiry,
             data_size: _,
             data,
+            noreply: _,
         } => {
-            if cache.contains_key(&key) {
-                println!("contains key");
-                lock_manager.get(&key).unwrap().read();
-                // check if expired
-                let db_item = cache.get(&key).unwrap();
-                let db_item = db_item.value();
-                lock_manager.get(&key).unwrap().read();
-                if !is_expired(&db_item) {
-                    return Err(anyhow!("NOT_STORED"));
+            if let Some(val) = cache.get(&key) {
+                if !is_expired(&cache, val.value()) {
+                    return Ok(Response::NotStored);
                 }
             }
 
-            return insert_key(key, expiry, data, cache, lock_manager);
+            return insert_key(key, flags, expiry, data, &cache, stats, memory);
         }
         Instruction::Replace {
             key,
+            flags,
             expiry,
             data_size: _,
             data,
+            noreply: _,
+        } => {
+            let insert_value = match cache.get(&key) {
+                Some(val) => !is_expired(&cache, val.value()),
+                None => false,
+            };
+            if insert_value {
+                return insert_key(key, flags, expiry, data, &cache, stats, memory);
+            } else {
+                return Ok(Response::NotStored);
+            }
+        }
+        Instruction::Cas {
+            key,
+            flags,
+            expiry,
+            data_size: _,
+            data,
+            cas_unique,
+            noreply: _,
         } => {
             let mut insert_value = false;
+            match cache.get(&key) {
+                Some(val) => {
+                    let db_item = val.value();
+                    if is_expired(&cache, db_item) {
+                        // Removing the key directly here can cause a deadlock
+                        key_to_delete = Some(key.clone());
+                    } else if db_item.cas != cas_unique {
+                        return Ok(Response::Exists);
+                    } else {
+                        insert_value = true;
+                    }
+                }
+                None => return Ok(Response::NotFound),
+            }
+            if insert_value {
+                return insert_key(key, flags, expiry, data, &cache, stats, memory);
+            }
+            Ok(Response::NotFound)
+        }
+        Instruction::Delete {
+            key, cas_unique, ..
+        } => {
+            if let (Some(cas_unique), Some(val)) = (cas_unique, cache.get(&key)) {
+                if !is_expired(&cache, val.value()) && val.value().cas != cas_unique {
+                    return Ok(Response::Exists);
+                }
+            }
+            match cache.remove(&key) {
+                Some((_, db_item)) => {
+                    memory.on_remove(&key, &db_item);
+                    if is_expired(&cache, &db_item) {
+                        Ok(Response::NotFound)
+                    } else {
+                        Ok(Response::Deleted)
+                    }
+                }
+                None => Ok(Response::NotFound),
+            }
+        }
+        Instruction::Touch { key, expiry, .. } => {
+            let expiry_milis = expiry_timestamp(expiry);
+            match cache.get_mut(&key) {
+                Some(mut val) => {
+                    let db_item = val.value_mut();
+                    if is_expired(&cache, db_item) {
+                        // Removing the key directly here can cause a deadlock
+                        key_to_delete = Some(key.clone());
+                        Ok(Response::NotFound)
+                    } else {
+                        db_item.expiry_secs = expiry;
+                        db_item.expiry_timestamp = expiry_milis;
+                        memory.on_access(&key, db_item);
+                        Ok(Response::Touched)
+                    }
+                }
+                None => Ok(Response::NotFound),
+            }
+        }
+        Instruction::FlushAll { delay, .. } => {
+            // Items are invalidated lazily by is_expired and reclaimed by the cleaner
+            if delay == 0 {
+                cache.flush(None);
+            } else {
+                let flush_timestamp = delay
+                    .checked_mul(1000)
+                    .and_then(|delay| current_millis().checked_add(delay))
+                    .and_then(|timestamp| u64::try_from(timestamp).ok())
+                    .ok_or_else(|| {
+                        anyhow!(ProtocolError::ClientError(
+                            "invalid exptime argument".to_owned()
+                        ))
+                    })?;
+                cache.flush(Some(flush_timestamp));
+            }
+            Ok(Response::Ok)
+        }
+        Instruction::Incr {
+            key,
+            delta,
+            initial,
+            ..
+        } => {
+            return update_counter(key, delta, true, initial, cache.clone(), stats, memory);
+        }
+        Instruction::Decr {
+            key,
+            delta,
+            initial,
+            ..
+        } => {
+            return update_counter(key, delta, false, initial, cache.clone(), stats, memory);
+        }
+        Instruction::Stats { group } => match group.as_deref() {
+            None => Ok(Response::Line(Bytes::from(stats.report(&cache, memory)?))),
+            Some("slabs") => Ok(Response::Line(Bytes::from(stats::report_slabs(memory)))),
+            Some(_) => Err(anyhow!(ProtocolError::UnknownCommand)),
+        },
+        Instruction::Version => Ok(Response::Line(Bytes::from(format!(
+            "VERSION {}",
+            env!("CARGO_PKG_VERSION")
+        )))),
+        Instruction::Verbosity { level, .. } => {
+            let level = match level {
+                0 => LevelFilter::Error,
+                1 => LevelFilter::Info,
+                2 => LevelFilter::Debug,
+                _ => LevelFilter::Trace,
+            };
+            log::set_max_level(level);
+            Ok(Response::Ok)
+        }
+        // The connection loop closes the connection or stops the server
+        Instruction::Quit => Err(anyhow!(NetError::ConnClosedByClient)),
+        Instruction::Shutdown => Err(anyhow!(NetError::ShutdownRequested)),
+        Instruction::MetaGet { .. }
+        | Instruction::MetaSet { .. }
+        | Instruction::MetaDelete { .. }
+        | Instruction::MetaArithmetic { .. }
+        | Instruction::MetaDebug { .. }
+        | Instruction::MetaNoop => unreachable!("meta commands are run by execute_meta"),
+    };
 
-            if lock_manager.contains_key(&key) {
-                lock_manager.get(&key).unwrap().write();
-                let db_item = cache.get(&key).unwrap();
-                let db_item = db_item.value();
-                if !is_expired(db_item) {
-                    insert_value = true;
+    if let Some(del) = key_to_delete {
+        remove_expired(&del, &cache, stats, memory);
+    }
+    res
+}
+
+/// Runs a meta command as the plain command it stands for, and turns the
+/// outcome into a reply with the flags the client asked for.
+fn execute_meta(
+    ins: Instruction,
+    cache: Db,
+    stats: Arc<Stats>,
+    memory: MemoryManager,
+) -> Result<Response> {
+    match ins {
+        Instruction::MetaGet { key, flags } => {
+            check_meta_flags(&flags, "bcfhklNOqRstTv")?;
+            let key = meta_key(key, &flags)?;
+            let vivify = meta_token::<i64>(&flags, 'N')?;
+            let recache = meta_token::<u128>(&flags, 'R')?;
+            let keys = vec![key.clone()];
+            let ins = match meta_token::<i64>(&flags, 'T')? {
+                Some(expiry) => Instruction::Gat { expiry, keys },
+                None => Instruction::Get { keys },
+            };
+            let fetch = |ins| match execute(ins, cache.clone(), stats.clone(), memory.clone()) {
+                Ok(Response::Values { mut values, .. }) => Ok(values.pop()),
+                Ok(_) => Ok(None),
+                Err(e) => Err(e),
+            };
+            let mut value = fetch(ins.clone())?;
+            let mut won = false;
+            if let (None, Some(expiry)) = (&value, vivify) {
+                value = match vivify_item(&key, expiry, &cache, &stats, &memory)? {
+                    Some(created) => {
+                        won = true;
+                        Some(created)
+                    }
+                    // Another client created it in the meantime
+                    None => fetch(ins)?,
                 };
             }
-            if insert_value {
-                return insert_key(key, expiry, data, cache.clone(), lock_manager);
+            let value = match value {
+                Some(value) => value,
+                None if flags.has('q') => return Ok(Response::Quiet),
+                None => return Ok(meta_reply("EN", &key, &flags, None, |_| None)),
+            };
+            let now = current_millis();
+            let lease = if won {
+                vec!["W"]
+            } else {
+                lease_flags(&key, &value, recache, now, &cache)
+            };
+            let reply = |flag| match flag {
+                'c' => Some(format!("c{}", value.cas)),
+                'f' => Some(format!("f{}", value.flags)),
+                'h' => Some(format!("h{}", value.fetched as u8)),
+                'l' => Some(format!(
+                    "l{}",
+                    now.saturating_sub(value.accessed_timestamp) / 1000
+                )),
+                's' => Some(format!("s{}", value.data.len())),
+                't' => Some(format!("t{}", ttl(value.expiry_timestamp, now))),
+                _ => None,
+            };
+            let reply = if flags.has('v') {
+                meta_reply("VA", &key, &flags, Some(value.data.clone()), reply)
+            } else {
+                meta_reply("HD", &key, &flags, None, reply)
+            };
+            Ok(with_flags(reply, lease))
+        }
+        Instruction::MetaSet {
+            key,
+            data_size,
+            data,
+            flags,
+        } => {
+            check_meta_flags(&flags, "bcCFIkMOqT")?;
+            let key = meta_key(key, &flags)?;
+            let client_flags = meta_token::<u32>(&flags, 'F')?.unwrap_or(0);
+            let expiry = meta_token::<i64>(&flags, 'T')?.unwrap_or(0);
+            let cas_unique = meta_token::<u64>(&flags, 'C')?;
+            let mode = flags.token('M').unwrap_or("S");
+            let invalidated_data = data.clone();
+            let ins = match (mode, cas_unique) {
+                ("S" | "s", Some(cas_unique)) => Instruction::Cas {
+                    key: key.clone(),
+                    flags: client_flags,
+                    expiry,
+                    data_size,
+                    data,
+                    cas_unique,
+                    noreply: false,
+                },
+                ("S" | "s", None) => Instruction::Set {
+                    key: key.clone(),
+                    flags: client_flags,
+                    expiry,
+                    data_size,
+                    data,
+                    noreply: false,
+                },
+                (_, Some(_)) => anyhow::bail!(ProtocolError::ClientError(
+                    "cas is only supported in set mode".to_owned()
+                )),
+                ("E" | "e", None) => Instruction::Add {
+                    key: key.clone(),
+                    flags: client_flags,
+                    expiry,
+                    data_size,
+                    data,
+                    noreply: false,
+                },
+                ("A" | "a", None) => Instruction::Append {
+                    key: key.clone(),
+                    flags: client_flags,
+                    expiry,
+                    data_size,
+                    data,
+                    noreply: false,
+                },
+                ("P" | "p", None) => Instruction::Prepend {
+                    key: key.clone(),
+                    flags: client_flags,
+                    expiry,
+                    data_size,
+                    data,
+                    noreply: false,
+                },
+                ("R" | "r", None) => Instruction::Replace {
+                    key: key.clone(),
+                    flags: client_flags,
+                    expiry,
+                    data_size,
+                    data,
+                    noreply: false,
+                },
+                _ => anyhow::bail!(ProtocolError::ClientError("invalid mode for ms".to_owned())),
+            };
+            let response = match execute(ins, cache.clone(), stats.clone(), memory.clone())? {
+                // A value computed before the item was invalidated is still
+                // stored, but stale, when the client asks for it with I
+                Response::Exists if flags.has('I') => {
+                    let _key_lock = cache.lock(&key);
+                    let invalidated = cache.get(&key).is_some_and(|val| {
+                        !is_expired(&cache, val.value())
+                            && cas_unique.is_some_and(|cas| cas < val.cas)
+                    });
+                    if invalidated {
+                        let value = allocate(&key, &[&invalidated_data], &cache, &stats, &memory)?;
+                        let mut db_item = new_item(client_flags, expiry, value, &cache);
+                        db_item.stale = true;
+                        store_item(key.clone(), db_item, &cache, &memory)?
+                    } else {
+                        Response::Exists
+                    }
+                }
+                response => response,
+            };
+            match response {
+                Response::Stored { .. } if flags.has('q') => Ok(Response::Quiet),
+                Response::Stored { cas } => Ok(meta_reply("HD", &key, &flags, None, |flag| {
+                    (flag == 'c').then(|| format!("c{cas}"))
+                })),
+                response => Ok(meta_reply(meta_code(&response), &key, &flags, None, |_| {
+                    None
+                })),
+            }
+        }
+        Instruction::MetaDelete { key, flags } => {
+            check_meta_flags(&flags, "bCIkOqT")?;
+            let key = meta_key(key, &flags)?;
+            let cas_unique = meta_token::<u64>(&flags, 'C')?;
+            let response = if flags.has('I') {
+                let expiry = meta_token::<i64>(&flags, 'T')?;
+                invalidate(&key, cas_unique, expiry, &cache, &stats)?
             } else {
-                return Err(anyhow!("NOT_STORED"));
+                let ins = Instruction::Delete {
+                    key: key.clone(),
+                    cas_unique,
+                    noreply: false,
+                };
+                execute(ins, cache, stats, memory)?
+            };
+            match response {
+                Response::Deleted | Response::NotFound if flags.has('q') => Ok(Response::Quiet),
+                response => Ok(meta_reply(meta_code(&response), &key, &flags, None, |_| {
+                    None
+                })),
+            }
+        }
+        Instruction::MetaArithmetic { key, flags } => {
+            check_meta_flags(&flags, "bDJkMNOqv")?;
+            let key = meta_key(key, &flags)?;
+            let delta = meta_token::<u64>(&flags, 'D')?.unwrap_or(1);
+            // A missing counter is only created when given a TTL to create it with
+            let initial = match meta_token::<i64>(&flags, 'N')? {
+                Some(expiry) => Some((meta_token::<u64>(&flags, 'J')?.unwrap_or(0), expiry)),
+                None => None,
+            };
+            let ins = match flags.token('M').unwrap_or("I") {
+                "I" | "i" | "+" => Instruction::Incr {
+                    key: key.clone(),
+                    delta,
+                    initial,
+                    noreply: false,
+                },
+                "D" | "d" | "-" => Instruction::Decr {
+                    key: key.clone(),
+                    delta,
+                    initial,
+                    noreply: false,
+                },
+                _ => anyhow::bail!(ProtocolError::ClientError("invalid mode for ma".to_owned())),
+            };
+            match execute(ins, cache, stats, memory)? {
+                Response::Counter { value, .. } if flags.has('v') => {
+                    Ok(meta_reply("VA", &key, &flags, Some(value), |_| None))
+                }
+                Response::Counter { .. } | Response::NotFound if flags.has('q') => {
+                    Ok(Response::Quiet)
+                }
+                response => Ok(meta_reply(meta_code(&response), &key, &flags, None, |_| {
+                    None
+                })),
+            }
+        }
+        Instruction::MetaDebug { key, flags } => {
+            check_meta_flags(&flags, "b")?;
+            let key = meta_key(key, &flags)?;
+            let line = match cache.get(&key) {
+                Some(val) if !is_expired(&cache, val.value()) => {
+                    let db_item = val.value();
+                    let now = current_millis();
+                    let accessed = db_item.accessed_timestamp.load(Ordering::Relaxed) as u128;
+                    let fetched = db_item.fetched.load(Ordering::Relaxed);
+                    let class = memory
+                        .class_for(&key, db_item.value.len())
+                        .unwrap_or_default();
+                    format!(
+                        "ME {} exp={} la={} cas={} fetch={} cls={} size={}",
+                        meta_flag_key(&key, &flags),
+                        ttl(db_item.expiry_timestamp, now),
+                        now.saturating_sub(accessed) / 1000,
+                        db_item.cas,
+                        if fetched { "yes" } else { "no" },
+                        // memcached numbers its slab classes from 1
+                        class + 1,
+                        db_item.value.len()
+                    )
+                }
+                _ => "EN".to_owned(),
+            };
+            Ok(Response::Line(Bytes::from(line)))
+        }
+        Instruction::MetaNoop => Ok(Response::Line(Bytes::from_static(b"MN"))),
+        _ => unreachable!("only meta commands are run by execute_meta"),
+    }
+}
+
+/// Creates an empty item for a missing key, handing the win token to the
+/// client creating it. Returns None if the key was stored in the meantime.
+fn vivify_item(
+    key: &str,
+    expiry: i64,
+    cache: &Db,
+    stats: &Stats,
+    memory: &Memory,
+) -> Result<Option<Value>> {
+    let _key_lock = cache.lock(key);
+    if let Some(val) = cache.get(key) {
+        if !is_expired(cache, val.value()) {
+            return Ok(None);
+        }
+    }
+    let value = allocate(key, &[], cache, stats, memory)?;
+    let db_item = new_item(0, expiry, value, cache);
+    db_item.win_token_sent.store(true, Ordering::Relaxed);
+    let created = Value {
+        key: key.to_owned(),
+        flags: db_item.flags,
+        cas: db_item.cas,
+        data: db_item.value.clone(),
+        expiry_timestamp: db_item.expiry_timestamp,
+        accessed_timestamp: db_item.stored_timestamp,
+        fetched: false,
+    };
+    store_item(key.to_owned(), db_item, cache, memory)?;
+    Ok(Some(created))
+}
+
+/// Marks an item stale instead of deleting it. It is still served, flagged
+/// X, while the first client to fetch it recomputes it.
+fn invalidate(
+    key: &str,
+    cas_unique: Option<u64>,
+    expiry: Option<i64>,
+    cache: &Db,
+    stats: &Stats,
+) -> Result<Response> {
+    let _key_lock = cache.lock(key);
+    let expiry = expiry.map(|expiry| (expiry, expiry_timestamp(expiry)));
+    let response = match cache.get_mut(key) {
+        Some(mut val) if !is_expired(cache, val.value()) => {
+            let db_item = val.value_mut();
+            if cas_unique.is_some_and(|cas| cas != db_item.cas) {
+                return Ok(Response::Exists);
             }
+            db_item.stale = true;
+            db_item.cas = cache.next_cas();
+            *db_item.win_token_sent.get_mut() = false;
+            if let Some((expiry, expiry_milis)) = expiry {
+                db_item.expiry_secs = expiry;
+                db_item.expiry_timestamp = expiry_milis;
+            }
+            stats::incr(&stats.delete_hits);
+            Response::Deleted
+        }
+        _ => {
+            stats::incr(&stats.delete_misses);
+            Response::NotFound
         }
     };
+    Ok(response)
+}
+
+/// Hands the right to recompute an item to the first client fetching it
+/// once it is stale, or has less than `recache` seconds left to live. The
+/// flags tell the client whether it won (W), another client already did
+/// (Z), and whether the value it got is stale (X).
+fn lease_flags(
+    key: &str,
+    value: &Value,
+    recache: Option<u128>,
+    now: u128,
+    cache: &Db,
+) -> Vec<&'static str> {
+    let val = match cache.get(key) {
+        Some(val) if val.value().cas == value.cas => val,
+        // Replaced since it was fetched, the value is as good as any
+        _ => return Vec::new(),
+    };
+    let db_item = val.value();
+    let expiring = recache.is_some_and(|secs| {
+        db_item.expiry_timestamp != 0 && ttl(db_item.expiry_timestamp, now) < secs as i128
+    });
+    let mut flags = Vec::new();
+    if db_item.stale || expiring {
+        if db_item.win_token_sent.swap(true, Ordering::Relaxed) {
+            flags.push("Z");
+        } else {
+            flags.push("W");
+        }
+    } else if db_item.win_token_sent.load(Ordering::Relaxed) {
+        flags.push("Z");
+    }
+    if db_item.stale {
+        flags.push("X");
+    }
+    flags
+}
+
+fn check_meta_flags(flags: &MetaFlags, allowed: &str) -> Result<()> {
+    if flags.iter().all(|(flag, _)| allowed.contains(flag)) {
+        Ok(())
+    } else {
+        Err(anyhow!(ProtocolError::ClientError(
+            "invalid flag".to_owned()
+        )))
+    }
+}
+
+fn meta_token<T: FromStr>(flags: &MetaFlags, flag: char) -> Result<Option<T>> {
+    match flags.token(flag) {
+        Some(token) => match token.parse::<T>() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(anyhow!(ProtocolError::ClientError(
+                "bad token in command line format".to_owned()
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The key of a meta command, which the `b` flag says is base64 encoded.
+fn meta_key(key: String, flags: &MetaFlags) -> Result<String> {
+    let key = if flags.has('b') {
+        BASE64
+            .decode(&key)
+            .ok()
+            .and_then(|key| String::from_utf8(key).ok())
+            .ok_or_else(|| anyhow!(ProtocolError::ClientError("error decoding key".to_owned())))?
+    } else {
+        key
+    };
+    if !instruction::is_valid_key(&key) {
+        anyhow::bail!(ProtocolError::ClientError(
+            "bad command line format".to_owned()
+        ));
+    }
+    Ok(key)
+}
+
+/// The key as sent back to the client, encoded again if it came in base64.
+fn meta_flag_key(key: &str, flags: &MetaFlags) -> String {
+    if flags.has('b') {
+        BASE64.encode(key)
+    } else {
+        key.to_owned()
+    }
+}
 
-    if let Some(del) = key_to_delete.clone() {
-        lock_manager.get(&del).unwrap().write();
-        cache.remove(&del);
+/// Builds a meta reply returning the flags asked for in the order they were
+/// given. The flags about the item come from `item_flag`.
+fn meta_reply(
+    code: &'static str,
+    key: &str,
+    flags: &MetaFlags,
+    value: Option<Bytes>,
+    item_flag: impl Fn(char) -> Option<String>,
+) -> Response {
+    let flags = flags
+        .iter()
+        .filter_map(|(flag, token)| match flag {
+            'O' => Some(format!("O{token}")),
+            'k' => Some(format!("k{}", meta_flag_key(key, flags))),
+            'b' if flags.has('k') => Some("b".to_owned()),
+            flag => item_flag(flag),
+        })
+        .collect();
+    Response::Meta { code, flags, value }
+}
+
+/// Adds flags the client did not ask for to a meta reply.
+fn with_flags(mut response: Response, extra: Vec<&str>) -> Response {
+    if let Response::Meta { flags, .. } = &mut response {
+        flags.extend(extra.into_iter().map(str::to_owned));
+    }
+    response
+}
+
+/// The meta return code for the outcome of a plain command.
+fn meta_code(response: &Response) -> &'static str {
+    match response {
+        Response::NotStored => "NS",
+        Response::Exists => "EX",
+        Response::NotFound => "NF",
+        _ => "HD",
+    }
+}
+
+/// Seconds an item has left to live, -1 if it never expires.
+fn ttl(expiry_timestamp: u128, now: u128) -> i128 {
+    if expiry_timestamp == 0 {
+        -1
+    } else {
+        expiry_timestamp.saturating_sub(now).div_ceil(1000) as i128
     }
+}
+
+fn get_values(
+    keys: Vec<String>,
+    with_cas: bool,
+    touch: Option<i64>,
+    cache: Db,
+    stats: &Stats,
+    memory: &Memory,
+) -> Result<Response> {
+    let touch_timestamp = touch.map(expiry_timestamp);
+    let mut values: Vec<Value> = Vec::new();
+    let mut expired_keys: Vec<String> = Vec::new();
+    for key in keys {
+        // Touching changes the item, so it waits for commands changing it
+        let _key_lock = touch.map(|_| cache.lock(&key));
+        let entry = match (touch, touch_timestamp) {
+            (Some(expiry), Some(timestamp)) => cache.get_mut(&key).map(|mut val| {
+                let db_item = val.value_mut();
+                if !is_expired(&cache, db_item) {
+                    db_item.expiry_secs = expiry;
+                    db_item.expiry_timestamp = timestamp;
+                }
+                val.downgrade()
+            }),
+            _ => cache.get(&key),
+        };
+        let val = match entry {
+            Some(val) => val,
+            None => {
+                record_get_miss(touch.is_some(), stats);
+                continue;
+            }
+        };
+        let db_item = val.value();
+        if is_expired(&cache, db_item) {
+            // Removing the key directly here can cause a deadlock
+            expired_keys.push(key);
+            record_get_miss(touch.is_some(), stats);
+            continue;
+        }
+        let fetched = db_item.fetched.swap(true, Ordering::Relaxed);
+        let accessed_timestamp = db_item
+            .accessed_timestamp
+            .swap(current_millis() as u64, Ordering::Relaxed);
+        memory.on_access(&key, db_item);
+        stats::incr(&stats.get_hits);
+        if touch.is_some() {
+            stats::incr(&stats.touch_hits);
+        }
 
-    if let Some(kdel) = key_to_delete {
-        lock_manager.remove(&kdel);
-        anyhow::bail!(key_delete_msg.unwrap());
+        values.push(Value {
+            key,
+            flags: db_item.flags,
+            cas: db_item.cas,
+            data: db_item.value.clone(),
+            expiry_timestamp: db_item.expiry_timestamp,
+            accessed_timestamp: accessed_timestamp as u128,
+            fetched,
+        });
+    }
+    for key in expired_keys {
+        remove_expired(&key, &cache, stats, memory);
+    }
+    Ok(Response::Values { values, with_cas })
+}
+
+fn record_get_miss(touch: bool, stats: &Stats) {
+    stats::incr(&stats.get_misses);
+    if touch {
+        stats::incr(&stats.touch_misses);
+    }
+}
+
+fn update_counter(
+    key: String,
+    delta: u64,
+    incr: bool,
+    initial: Option<(u64, i64)>,
+    cache: Db,
+    stats: &Stats,
+    memory: &Memory,
+) -> Result<Response> {
+    let mut expired = false;
+    let current = match cache.get(&key) {
+        Some(val) => {
+            let db_item = val.value();
+            if is_expired(&cache, db_item) {
+                // Removing the key directly here can cause a deadlock
+                expired = true;
+                None
+            } else {
+                match std::str::from_utf8(&db_item.value)
+                    .ok()
+                    .and_then(|value| value.parse::<u64>().ok())
+                {
+                    Some(current) => Some(current),
+                    None => anyhow::bail!(ProtocolError::NonNumeric),
+                }
+            }
+        }
+        None => None,
+    };
+    let res = match current {
+        Some(current) => {
+            // Increments wrap around at 64 bits, decrements stop at 0
+            let updated = if incr {
+                current.wrapping_add(delta)
+            } else {
+                current.saturating_sub(delta)
+            };
+            let updated = updated.to_string();
+            // Allocating can evict, so no entry of the cache may be held here
+            let value = allocate(&key, &[updated.as_bytes()], &cache, stats, memory)?;
+            match cache.get_mut(&key) {
+                Some(mut val) => {
+                    let db_item = val.value_mut();
+                    memory.on_remove(&key, db_item);
+                    db_item.value = value;
+                    db_item.cas = cache.next_cas();
+                    db_item.stored_timestamp = current_millis();
+                    db_item.fetched.store(true, Ordering::Relaxed);
+                    memory.on_insert(&key, db_item);
+                    Ok(Response::Counter {
+                        value: Bytes::from(updated),
+                        cas: db_item.cas,
+                    })
+                }
+                None => Ok(Response::NotFound),
+            }
+        }
+        None => Ok(Response::NotFound),
+    };
+
+    if expired {
+        remove_expired(&key, &cache, stats, memory);
+    }
+    if let (Ok(Response::NotFound), Some((initial, expiry))) = (&res, initial) {
+        let value = Bytes::from(initial.to_string());
+        let cas = match insert_key(key, 0, expiry, value.clone(), &cache, stats, memory)? {
+            Response::Stored { cas } => cas,
+            _ => 0,
+        };
+        return Ok(Response::Counter { value, cas });
     }
     res
 }
 
-fn is_expired(db_item: &DBItem) -> bool {
-    let current_time = SystemTime::now()
+fn remove_expired(key: &str, cache: &Db, stats: &Stats, memory: &Memory) {
+    // The key may have been stored again since it was found expired
+    if let Some((_, db_item)) = cache.remove_if(key, |_, item| is_expired(cache, item)) {
+        memory.on_remove(key, &db_item);
+        if !db_item.fetched.load(Ordering::Relaxed) {
+            stats::incr(&stats.expired_unfetched);
+        }
+    }
+}
+
+/// Copies a value made of the given parts into a slab chunk, evicting items
+/// of the same slab class until one is free.
+fn allocate(
+    key: &str,
+    parts: &[&[u8]],
+    cache: &Db,
+    stats: &Stats,
+    memory: &Memory,
+) -> Result<Bytes> {
+    let size = parts.iter().map(|part| part.len()).sum();
+    let class = match memory.class_for(key, size) {
+        Some(class) => class,
+        None => anyhow::bail!(ProtocolError::TooLarge),
+    };
+    loop {
+        if let Some(value) = memory.store(class, parts) {
+            return Ok(value);
+        }
+        let key = match memory.victim(class) {
+            Some(key) => key,
+            None => anyhow::bail!(ProtocolError::OutOfMemory),
+        };
+        // The victim is not locked, a command holding its lock may store it again
+        if let Some((_, db_item)) = cache.remove(&key) {
+            memory.on_remove(&key, &db_item);
+            stats::incr(&stats.evictions);
+        }
+    }
+}
+
+/// The key of a command that changes a single item.
+fn locked_key(ins: &Instruction) -> Option<&str> {
+    match ins {
+        Instruction::Set { key, .. }
+        | Instruction::Add { key, .. }
+        | Instruction::Replace { key, .. }
+        | Instruction::Append { key, .. }
+        | Instruction::Prepend { key, .. }
+        | Instruction::Cas { key, .. }
+        | Instruction::Delete { key, .. }
+        | Instruction::Touch { key, .. }
+        | Instruction::Incr { key, .. }
+        | Instruction::Decr { key, .. } => Some(key),
+        _ => None,
+    }
+}
+
+/// Converts an exptime the way memcached does: 0 never expires, negative
+/// times expire immediately and times over 30 days are Unix timestamps.
+fn expiry_timestamp(expiry: i64) -> u128 {
+    match expiry {
+        0 => 0,
+        ..0 => 1,
+        1..=REALTIME_MAXDELTA => current_millis() + expiry as u128 * 1000,
+        _ => expiry as u128 * 1000,
+    }
+}
+
+pub fn is_expired(cache: &Store, db_item: &DBItem) -> bool {
+    let current_time = current_millis();
+    if current_time > db_item.expiry_timestamp && db_item.expiry_timestamp != 0 {
+        return true;
+    }
+    // Items stored before a flush_all that has taken effect are invalid
+    cache.is_flushed(db_item, current_time)
+}
+
+pub fn current_millis() -> u128 {
+    // A clock set before the epoch is treated as the epoch rather than panicking
+    SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .context("TIME ERROR")
-        .unwrap()
-        .as_millis();
-    current_time > db_item.expiry_timestamp && db_item.expiry_timestamp != 0
+        .unwrap_or_default()
+        .as_millis()
 }
 
 fn insert_key(
     key: String,
-    expiry: u128,
+    flags: u32,
+    expiry: i64,
     data: Bytes,
-    cache: Db,
-    lock_manager: LockManager,
-) -> Result<String> {
-    let mut expiry_milis = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .context("SYSTEM_ERROR")?
-        .as_millis()
-        + expiry * 1000;
-    if expiry == 0 {
-        expiry_milis = 0;
-    }
-    cache.insert(
-        key.clone(),
-        DBItem {
-            expiry_secs: expiry,
-            expiry_timestamp: expiry_milis,
-            value: data,
-        },
-    );
-    lock_manager.insert(key, RwLock::new(true));
-    Ok("STORED".to_owned())
+    cache: &Db,
+    stats: &Stats,
+    memory: &Memory,
+) -> Result<Response> {
+    let value = allocate(&key, &[&data], cache, stats, memory)?;
+    let db_item = new_item(flags, expiry, value, cache);
+    store_item(key, db_item, cache, memory)
+}
+
+fn new_item(flags: u32, expiry: i64, value: Bytes, cache: &Store) -> DBItem {
+    DBItem {
+        flags,
+        expiry_secs: expiry,
+        expiry_timestamp: expiry_timestamp(expiry),
+        cas: cache.next_cas(),
+        stored_timestamp: current_millis(),
+        accessed_timestamp: AtomicU64::new(current_millis() as u64),
+        fetched: AtomicBool::new(false),
+        stale: false,
+        win_token_sent: AtomicBool::new(false),
+        value,
+    }
+}
+
+fn store_item(key: String, db_item: DBItem, cache: &Db, memory: &Memory) -> Result<Response> {
+    let cas = db_item.cas;
+    match cache.entry(key.clone()) {
+        Entry::Occupied(mut entry) => {
+            let replaced = entry.insert(db_item);
+            memory.on_remove(&key, &replaced);
+            memory.on_insert(&key, entry.get());
+        }
+        Entry::Vacant(entry) => memory.on_insert(&key, entry.insert(db_item).value()),
+    }
+    Ok(Response::Stored { cas })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{eviction::PolicyKind, store::Store};
+
+    const THREADS: usize = 8;
+    const OPS: usize = 500;
+
+    fn setup() -> (Db, Arc<Stats>, MemoryManager) {
+        let memory = Memory::new(64 * 1024 * 1024, 1.25, PolicyKind::Lru);
+        (
+            Arc::new(Store::new()),
+            Arc::new(Stats::new()),
+            Arc::new(memory),
+        )
+    }
+
+    fn set(key: &str, data: &'static [u8], cache: &Db, stats: &Arc<Stats>, memory: &MemoryManager) {
+        let ins = Instruction::Set {
+            key: key.to_owned(),
+            flags: 0,
+            expiry: 0,
+            data_size: data.len(),
+            data: Bytes::from_static(data),
+            noreply: false,
+        };
+        execute(ins, cache.clone(), stats.clone(), memory.clone()).unwrap();
+    }
+
+    fn get(key: &str, cache: &Db, stats: &Arc<Stats>, memory: &MemoryManager) -> Bytes {
+        let ins = Instruction::Get {
+            keys: vec![key.to_owned()],
+        };
+        match execute(ins, cache.clone(), stats.clone(), memory.clone()).unwrap() {
+            Response::Values { mut values, .. } => values.pop().unwrap().data,
+            response => panic!("unexpected response {response:?}"),
+        }
+    }
+
+    /// Runs `OPS` instructions made by `ins` on each of `THREADS` threads at once.
+    fn hammer(
+        ins: impl Fn() -> Instruction + Send + Sync,
+        cache: &Db,
+        stats: &Arc<Stats>,
+        memory: &MemoryManager,
+    ) {
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for _ in 0..OPS {
+                        execute(ins(), cache.clone(), stats.clone(), memory.clone()).unwrap();
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn concurrent_appends_are_not_lost() {
+        let (cache, stats, memory) = setup();
+        set("log", b"", &cache, &stats, &memory);
+        let append = || Instruction::Append {
+            key: "log".to_owned(),
+            flags: 0,
+            expiry: 0,
+            data_size: 1,
+            data: Bytes::from_static(b"x"),
+            noreply: false,
+        };
+        hammer(append, &cache, &stats, &memory);
+        assert_eq!(get("log", &cache, &stats, &memory).len(), THREADS * OPS);
+    }
+
+    #[test]
+    fn concurrent_increments_are_not_lost() {
+        let (cache, stats, memory) = setup();
+        set("counter", b"0", &cache, &stats, &memory);
+        let incr = || Instruction::Incr {
+            key: "counter".to_owned(),
+            delta: 1,
+            initial: None,
+            noreply: false,
+        };
+        hammer(incr, &cache, &stats, &memory);
+        let counter = get("counter", &cache, &stats, &memory);
+        assert_eq!(counter, (THREADS * OPS).to_string().as_bytes());
+    }
+
+    #[test]
+    fn long_keys_stay_within_the_memory_limit() {
+        let (cache, stats, _) = setup();
+        let memory = Arc::new(Memory::new(4 * 1024 * 1024, 1.25, PolicyKind::Lru));
+        for i in 0..50_000 {
+            let key = format!("{i:0>250}");
+            set(&key, b"v", &cache, &stats, &memory);
+        }
+        assert!(stats.evictions.load(Ordering::Relaxed) > 0);
+        assert!(memory.used() <= memory.limit());
+    }
+
+    #[test]
+    fn exptimes_follow_memcached_rules() {
+        let now = current_millis();
+        assert_eq!(expiry_timestamp(0), 0);
+        assert!(expiry_timestamp(-1) < now);
+        let relative = expiry_timestamp(REALTIME_MAXDELTA);
+        assert!(relative >= now + REALTIME_MAXDELTA as u128 * 1000);
+        let absolute = now / 1000 + 60;
+        assert_eq!(expiry_timestamp(absolute as i64), absolute * 1000);
+        assert!(expiry_timestamp(REALTIME_MAXDELTA + 1) < now);
+    }
 }
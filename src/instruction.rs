@@ -1,283 +1,760 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 use bytes::Bytes;
 
 use crate::error::ParseError;
 
+pub const MAX_KEY_LENGTH: usize = 250;
+
 #[derive(Debug, Clone)]
 pub enum Instruction {
     Set {
         key: String,
-        expiry: u128,
+        flags: u32,
+        expiry: i64,
         data_size: usize,
         data: Bytes,
+        noreply: bool,
     },
     Get {
-        key: String,
+        keys: Vec<String>,
     },
     Append {
         key: String,
-        expiry: u128,
+        flags: u32,
+        expiry: i64,
         data_size: usize,
         data: Bytes,
+        noreply: bool,
     },
     Prepend {
         key: String,
-        expiry: u128,
+        flags: u32,
+        expiry: i64,
         data_size: usize,
         data: Bytes,
+        noreply: bool,
     },
     Add {
         key: String,
-        expiry: u128,
+        flags: u32,
+        expiry: i64,
         data_size: usize,
         data: Bytes,
+        noreply: bool,
     },
     Replace {
         key: String,
-        expiry: u128,
+        flags: u32,
+        expiry: i64,
+        data_size: usize,
+        data: Bytes,
+        noreply: bool,
+    },
+    Gets {
+        keys: Vec<String>,
+    },
+    Cas {
+        key: String,
+        flags: u32,
+        expiry: i64,
         data_size: usize,
         data: Bytes,
+        cas_unique: u64,
+        noreply: bool,
+    },
+    Delete {
+        key: String,
+        // Only deletes an item with this CAS value
+        cas_unique: Option<u64>,
+        noreply: bool,
+    },
+    Touch {
+        key: String,
+        expiry: i64,
+        noreply: bool,
+    },
+    Gat {
+        expiry: i64,
+        keys: Vec<String>,
+    },
+    Gats {
+        expiry: i64,
+        keys: Vec<String>,
+    },
+    FlushAll {
+        delay: u128,
+        noreply: bool,
     },
+    Incr {
+        key: String,
+        delta: u64,
+        // Value and expiry to create a missing counter with
+        initial: Option<(u64, i64)>,
+        noreply: bool,
+    },
+    Decr {
+        key: String,
+        delta: u64,
+        initial: Option<(u64, i64)>,
+        noreply: bool,
+    },
+    Stats {
+        group: Option<String>,
+    },
+    Version,
+    Verbosity {
+        level: u32,
+        noreply: bool,
+    },
+    Quit,
+    Shutdown,
+    MetaGet {
+        key: String,
+        flags: MetaFlags,
+    },
+    MetaSet {
+        key: String,
+        data_size: usize,
+        data: Bytes,
+        flags: MetaFlags,
+    },
+    MetaDelete {
+        key: String,
+        flags: MetaFlags,
+    },
+    MetaArithmetic {
+        key: String,
+        flags: MetaFlags,
+    },
+    MetaDebug {
+        key: String,
+        flags: MetaFlags,
+    },
+    MetaNoop,
+}
+
+/// The flags of a meta command, each a letter followed by an optional token,
+/// such as `v` or `T30`. Which flags a command accepts is checked when it runs.
+#[derive(Debug, Clone, Default)]
+pub struct MetaFlags {
+    flags: Vec<(char, String)>,
+}
+
+impl MetaFlags {
+    fn parse<'a>(parts: impl Iterator<Item = &'a str>) -> MetaFlags {
+        let flags = parts
+            .map(|part| {
+                let mut chars = part.chars();
+                let flag = chars.next().unwrap_or_default();
+                (flag, chars.as_str().to_string())
+            })
+            .collect();
+        MetaFlags { flags }
+    }
+
+    /// The flags with their tokens, in the order they were given.
+    pub fn iter(&self) -> impl Iterator<Item = (char, &str)> {
+        self.flags
+            .iter()
+            .map(|(flag, token)| (*flag, token.as_str()))
+    }
+
+    pub fn has(&self, flag: char) -> bool {
+        self.flags.iter().any(|(f, _)| *f == flag)
+    }
+
+    pub fn token(&self, flag: char) -> Option<&str> {
+        self.flags
+            .iter()
+            .find(|(f, _)| *f == flag)
+            .map(|(_, token)| token.as_str())
+    }
+}
+
+impl Instruction {
+    /// Whether the client asked not to be sent a reply for this instruction.
+    pub fn noreply(&self) -> bool {
+        match self {
+            Instruction::Set { noreply, .. }
+            | Instruction::Append { noreply, .. }
+            | Instruction::Prepend { noreply, .. }
+            | Instruction::Add { noreply, .. }
+            | Instruction::Replace { noreply, .. }
+            | Instruction::Cas { noreply, .. }
+            | Instruction::Delete { noreply, .. }
+            | Instruction::Touch { noreply, .. }
+            | Instruction::FlushAll { noreply, .. }
+            | Instruction::Incr { noreply, .. }
+            | Instruction::Decr { noreply, .. }
+            | Instruction::Verbosity { noreply, .. } => *noreply,
+            _ => false,
+        }
+    }
 }
 
 pub fn complete_ins(ins: Instruction, data: Bytes) -> Instruction {
     match ins {
         Instruction::Set {
             key,
+            flags,
             expiry,
             data_size,
             data: _,
-        } => {
-            return Instruction::Set {
-                key,
-                expiry,
-                data_size,
-                data,
-            };
-        }
+            noreply,
+        } => Instruction::Set {
+            key,
+            flags,
+            expiry,
+            data_size,
+            data,
+            noreply,
+        },
         Instruction::Append {
             key,
+            flags,
             expiry,
             data_size,
             data: _,
-        } => {
-            return Instruction::Append {
-                key,
-                expiry,
-                data_size,
-                data,
-            };
-        }
+            noreply,
+        } => Instruction::Append {
+            key,
+            flags,
+            expiry,
+            data_size,
+            data,
+            noreply,
+        },
         Instruction::Prepend {
             key,
+            flags,
             expiry,
             data_size,
             data: _,
-        } => {
-            return Instruction::Prepend {
-                key,
-                expiry,
-                data_size,
-                data,
-            };
-        }
+            noreply,
+        } => Instruction::Prepend {
+            key,
+            flags,
+            expiry,
+            data_size,
+            data,
+            noreply,
+        },
         Instruction::Add {
             key,
+            flags,
             expiry,
             data_size,
             data: _,
-        } => {
-            return Instruction::Add {
-                key,
-                expiry,
-                data_size,
-                data,
-            };
-        }
+            noreply,
+        } => Instruction::Add {
+            key,
+            flags,
+            expiry,
+            data_size,
+            data,
+            noreply,
+        },
         Instruction::Replace {
             key,
+            flags,
             expiry,
             data_size,
             data: _,
-        } => {
-            return Instruction::Replace {
-                key,
-                expiry,
-                data_size,
-                data,
-            };
-        }
-        _ => {
-            return ins;
-        }
-    };
-}
-
-pub fn parse_ins_with_data(line: String, data: Bytes) -> Result<Instruction> {
-    let parts = line.split_whitespace();
-    let mut parts = parts.into_iter();
-    match parts.next() {
-        Some("set") | Some("append") | Some("prepend") => match parse_string(line) {
-            Ok(ins) => {
-                return Ok(complete_ins(ins, data));
-            }
-            Err(err) => match err.downcast_ref() {
-                Some(ParseError::InsufficientWaiting(ins, _)) => {
-                    return Ok(complete_ins(ins.clone(), data));
-                }
-                _ => return Err(err),
-            },
+            noreply,
+        } => Instruction::Replace {
+            key,
+            flags,
+            expiry,
+            data_size,
+            data,
+            noreply,
+        },
+        Instruction::Cas {
+            key,
+            flags,
+            expiry,
+            data_size,
+            data: _,
+            cas_unique,
+            noreply,
+        } => Instruction::Cas {
+            key,
+            flags,
+            expiry,
+            data_size,
+            data,
+            cas_unique,
+            noreply,
         },
-        _ => return Err(anyhow!(ParseError::InvalidInstruction)),
-    };
+        Instruction::MetaSet {
+            key,
+            data_size,
+            data: _,
+            flags,
+        } => Instruction::MetaSet {
+            key,
+            data_size,
+            data,
+            flags,
+        },
+        _ => ins,
+    }
 }
 
 pub fn parse_string(line: String) -> Result<Instruction> {
-    let parts = line.split_whitespace();
-    let mut parts = parts.into_iter();
+    let mut parts = line.split_whitespace();
     match parts.next() {
         Some("set") => {
             let key = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
+                .ok_or(ParseError::InvalidInstruction)?
                 .to_string();
+            let flags = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<u32>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
             let expiry = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
-                .parse::<u128>()
-                .context(anyhow!(ParseError::InvalidInstruction))?;
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<i64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
             let data_size = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
+                .ok_or(ParseError::InvalidInstruction)?
                 .parse::<usize>()
-                .context(anyhow!(ParseError::InvalidInstruction))?;
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let noreply = parse_noreply(parts.next())?;
+            check_key(&key, Some(data_size))?;
 
             let iw = anyhow!(ParseError::InsufficientWaiting(
                 Instruction::Set {
                     key,
+                    flags,
                     expiry,
                     data_size,
                     data: Bytes::new(),
+                    noreply,
                 },
                 data_size
             ));
-            return Err(iw);
+            Err(iw)
         }
         Some("get") => {
-            let key = parts
-                .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
-                .to_string();
-            return Ok(Instruction::Get { key });
+            let keys: Vec<String> = parts.map(|key| key.to_string()).collect();
+            if keys.is_empty() {
+                anyhow::bail!(ParseError::InvalidInstruction);
+            }
+            for key in &keys {
+                check_key(key, None)?;
+            }
+            Ok(Instruction::Get { keys })
+        }
+        Some("gets") => {
+            let keys: Vec<String> = parts.map(|key| key.to_string()).collect();
+            if keys.is_empty() {
+                anyhow::bail!(ParseError::InvalidInstruction);
+            }
+            for key in &keys {
+                check_key(key, None)?;
+            }
+            Ok(Instruction::Gets { keys })
         }
         Some("append") => {
             let key = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
+                .ok_or(ParseError::InvalidInstruction)?
                 .to_string();
+            let flags = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<u32>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
             let expiry = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
-                .parse::<u128>()
-                .context(anyhow!(ParseError::InvalidInstruction))?;
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<i64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
             let data_size = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
+                .ok_or(ParseError::InvalidInstruction)?
                 .parse::<usize>()
-                .context(anyhow!(ParseError::InvalidInstruction))?;
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let noreply = parse_noreply(parts.next())?;
+            check_key(&key, Some(data_size))?;
 
             let iw = anyhow!(ParseError::InsufficientWaiting(
                 Instruction::Append {
                     key,
+                    flags,
                     expiry,
                     data_size,
                     data: Bytes::new(),
+                    noreply,
                 },
                 data_size
             ));
-            return Err(iw);
+            Err(iw)
         }
         Some("prepend") => {
             let key = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
+                .ok_or(ParseError::InvalidInstruction)?
                 .to_string();
+            let flags = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<u32>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
             let expiry = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
-                .parse::<u128>()
-                .context(anyhow!(ParseError::InvalidInstruction))?;
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<i64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
             let data_size = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
+                .ok_or(ParseError::InvalidInstruction)?
                 .parse::<usize>()
-                .context(anyhow!(ParseError::InvalidInstruction))?;
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let noreply = parse_noreply(parts.next())?;
+            check_key(&key, Some(data_size))?;
 
             let iw = anyhow!(ParseError::InsufficientWaiting(
                 Instruction::Prepend {
                     key,
+                    flags,
                     expiry,
                     data_size,
                     data: Bytes::new(),
+                    noreply,
                 },
                 data_size
             ));
-            return Err(iw);
+            Err(iw)
         }
         Some("add") => {
             let key = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
+                .ok_or(ParseError::InvalidInstruction)?
                 .to_string();
+            let flags = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<u32>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
             let expiry = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
-                .parse::<u128>()
-                .context(anyhow!(ParseError::InvalidInstruction))?;
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<i64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
             let data_size = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
+                .ok_or(ParseError::InvalidInstruction)?
                 .parse::<usize>()
-                .context(anyhow!(ParseError::InvalidInstruction))?;
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let noreply = parse_noreply(parts.next())?;
+            check_key(&key, Some(data_size))?;
 
             let iw = anyhow!(ParseError::InsufficientWaiting(
                 Instruction::Add {
                     key,
+                    flags,
                     expiry,
                     data_size,
                     data: Bytes::new(),
+                    noreply,
                 },
                 data_size
             ));
-            return Err(iw);
+            Err(iw)
         }
         Some("replace") => {
             let key = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
+                .ok_or(ParseError::InvalidInstruction)?
                 .to_string();
+            let flags = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<u32>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
             let expiry = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
-                .parse::<u128>()
-                .context(anyhow!(ParseError::InvalidInstruction))?;
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<i64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
             let data_size = parts
                 .next()
-                .context(anyhow!(ParseError::InvalidInstruction))?
+                .ok_or(ParseError::InvalidInstruction)?
                 .parse::<usize>()
-                .context(anyhow!(ParseError::InvalidInstruction))?;
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let noreply = parse_noreply(parts.next())?;
+            check_key(&key, Some(data_size))?;
 
             let iw = anyhow!(ParseError::InsufficientWaiting(
                 Instruction::Replace {
                     key,
+                    flags,
+                    expiry,
+                    data_size,
+                    data: Bytes::new(),
+                    noreply,
+                },
+                data_size
+            ));
+            Err(iw)
+        }
+        Some("cas") => {
+            let key = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .to_string();
+            let flags = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<u32>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let expiry = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<i64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let data_size = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<usize>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let cas_unique = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<u64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let noreply = parse_noreply(parts.next())?;
+            check_key(&key, Some(data_size))?;
+
+            let iw = anyhow!(ParseError::InsufficientWaiting(
+                Instruction::Cas {
+                    key,
+                    flags,
                     expiry,
                     data_size,
                     data: Bytes::new(),
+                    cas_unique,
+                    noreply,
+                },
+                data_size
+            ));
+            Err(iw)
+        }
+        Some("delete") => {
+            let key = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .to_string();
+            let noreply = parse_noreply(parts.next())?;
+            check_key(&key, None)?;
+            Ok(Instruction::Delete {
+                key,
+                cas_unique: None,
+                noreply,
+            })
+        }
+        Some("touch") => {
+            let key = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .to_string();
+            let expiry = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<i64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let noreply = parse_noreply(parts.next())?;
+            check_key(&key, None)?;
+            Ok(Instruction::Touch {
+                key,
+                expiry,
+                noreply,
+            })
+        }
+        Some("gat") => {
+            let expiry = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<i64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let keys: Vec<String> = parts.map(|key| key.to_string()).collect();
+            if keys.is_empty() {
+                anyhow::bail!(ParseError::InvalidInstruction);
+            }
+            for key in &keys {
+                check_key(key, None)?;
+            }
+            Ok(Instruction::Gat { expiry, keys })
+        }
+        Some("gats") => {
+            let expiry = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<i64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let keys: Vec<String> = parts.map(|key| key.to_string()).collect();
+            if keys.is_empty() {
+                anyhow::bail!(ParseError::InvalidInstruction);
+            }
+            for key in &keys {
+                check_key(key, None)?;
+            }
+            Ok(Instruction::Gats { expiry, keys })
+        }
+        Some("flush_all") => {
+            let (delay, noreply) = match parts.next() {
+                Some("noreply") => (0, true),
+                Some(delay) => (
+                    delay
+                        .parse::<u128>()
+                        .map_err(|_| ParseError::InvalidInstruction)?,
+                    parse_noreply(parts.next())?,
+                ),
+                None => (0, false),
+            };
+            Ok(Instruction::FlushAll { delay, noreply })
+        }
+        Some("incr") => {
+            let key = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .to_string();
+            let delta = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<u64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let noreply = parse_noreply(parts.next())?;
+            check_key(&key, None)?;
+            Ok(Instruction::Incr {
+                key,
+                delta,
+                initial: None,
+                noreply,
+            })
+        }
+        Some("decr") => {
+            let key = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .to_string();
+            let delta = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<u64>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let noreply = parse_noreply(parts.next())?;
+            check_key(&key, None)?;
+            Ok(Instruction::Decr {
+                key,
+                delta,
+                initial: None,
+                noreply,
+            })
+        }
+        Some("stats") => Ok(Instruction::Stats {
+            group: parts.next().map(|group| group.to_string()),
+        }),
+        Some("version") => Ok(Instruction::Version),
+        Some("verbosity") => {
+            let level = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<u32>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let noreply = parse_noreply(parts.next())?;
+            Ok(Instruction::Verbosity { level, noreply })
+        }
+        Some("quit") => Ok(Instruction::Quit),
+        Some("shutdown") => Ok(Instruction::Shutdown),
+        Some("mg") => {
+            let key = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .to_string();
+            let flags = MetaFlags::parse(parts);
+            Ok(Instruction::MetaGet { key, flags })
+        }
+        Some("ms") => {
+            let key = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .to_string();
+            let data_size = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .parse::<usize>()
+                .map_err(|_| ParseError::InvalidInstruction)?;
+            let flags = MetaFlags::parse(parts);
+
+            let iw = anyhow!(ParseError::InsufficientWaiting(
+                Instruction::MetaSet {
+                    key,
+                    data_size,
+                    data: Bytes::new(),
+                    flags,
                 },
                 data_size
             ));
-            return Err(iw);
+            Err(iw)
+        }
+        Some("md") => {
+            let key = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .to_string();
+            let flags = MetaFlags::parse(parts);
+            Ok(Instruction::MetaDelete { key, flags })
+        }
+        Some("ma") => {
+            let key = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .to_string();
+            let flags = MetaFlags::parse(parts);
+            Ok(Instruction::MetaArithmetic { key, flags })
+        }
+        Some("me") => {
+            let key = parts
+                .next()
+                .ok_or(ParseError::InvalidInstruction)?
+                .to_string();
+            let flags = MetaFlags::parse(parts);
+            Ok(Instruction::MetaDebug { key, flags })
         }
-        _ => return Err(anyhow!(ParseError::InvalidInstruction)),
-    };
+        Some("mn") => Ok(Instruction::MetaNoop),
+        _ => Err(anyhow!(ParseError::UnknownCommand)),
+    }
+}
+
+/// Whether a key can be stored: at most 250 bytes, without whitespace or
+/// control characters, the same in every protocol.
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_LENGTH
+        && !key.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Rejects an invalid key. The data block of a storage command is thrown
+/// away with it.
+fn check_key(key: &str, data_size: Option<usize>) -> Result<()> {
+    if is_valid_key(key) {
+        return Ok(());
+    }
+    let swallow = data_size.map_or(0, |data_size| data_size.saturating_add(2));
+    Err(anyhow!(ParseError::InvalidKey(swallow)))
+}
+
+fn parse_noreply(part: Option<&str>) -> Result<bool> {
+    match part {
+        Some("noreply") => Ok(true),
+        Some(_) => Err(anyhow!(ParseError::InvalidInstruction)),
+        None => Ok(false),
+    }
 }
@@ -1,55 +1,151 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    env, fs,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc,
+    },
+};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
-use clap::{command, Parser};
-use dashmap::DashMap;
-use error::{CleanupError, NetError};
-use log::{error, info};
-use tokio::time::{sleep, Duration};
+use clap::Parser;
+use error::{CleanupError, NetError, ParseError, ProtocolError};
+use log::{error, info, LevelFilter};
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket, UnixListener},
+    sync::Notify,
+    task::JoinSet,
+    time::{sleep, Duration},
+};
 
-use crate::connection::Connection;
+use crate::{
+    connection::Connection,
+    eviction::{Memory, PolicyKind},
+    stats::Stats,
+    store::Store,
+};
 
+mod binary;
 mod cleaner;
 mod connection;
 mod error;
+mod eviction;
 mod executor;
 mod instruction;
+mod response;
+mod slabs;
+mod stats;
+mod store;
+mod udp;
 
-const NUM_SHARDS: usize = 32;
 const CLEANUP_GAP: u64 = 10;
 
 struct DBItem {
+    flags: u32,
     expiry_timestamp: u128,
-    expiry_secs: u128,
+    expiry_secs: i64,
+    cas: u64,
+    stored_timestamp: u128,
+    accessed_timestamp: AtomicU64,
+    fetched: AtomicBool,
+    // Invalidated by a meta delete, still served until it is set again
+    stale: bool,
+    // Whether a client was told to recompute the item
+    win_token_sent: AtomicBool,
     value: Bytes,
 }
 
-type Db = Arc<DashMap<String, DBItem>>;
-type LockManager = Arc<DashMap<String, RwLock<bool>>>;
+type Db = Arc<Store>;
+type MemoryManager = Arc<Memory>;
 
 #[derive(Parser, Debug)]
 #[command(author="Ankush", version="0.1.0", about = None, long_about = None)]
 struct Args {
     #[arg(short, long, default_value = "11211")]
     port: Option<u16>,
+
+    /// Addresses to listen on, e.g. 0.0.0.0 or [::], with an optional port.
+    /// Can be repeated or comma separated
+    #[arg(short, long, value_delimiter = ',', default_value = "127.0.0.1")]
+    listen: Vec<String>,
+
+    /// Port to answer UDP requests on, using the listen addresses, 0 to disable UDP
+    #[arg(short = 'U', long, default_value_t = 0)]
+    udp_port: u16,
+
+    /// Also accept connections on a unix domain socket at this path
+    #[arg(short = 's', long)]
+    unix_socket: Option<PathBuf>,
+
+    /// Permissions of the unix domain socket, in octal
+    #[arg(short = 'a', long, default_value = "0700", value_parser = parse_unix_mask)]
+    unix_mask: u32,
+
+    /// Memory to use for items in megabytes, 0 for no limit
+    #[arg(short, long, default_value_t = 64)]
+    memory_limit: u64,
+
+    /// Allow clients to stop the server with the shutdown command
+    #[arg(long, default_value_t = false)]
+    enable_shutdown: bool,
+
+    /// Ratio between the chunk sizes of consecutive slab classes
+    #[arg(short = 'f', long, default_value_t = 1.25, value_parser = parse_growth_factor)]
+    growth_factor: f64,
+
+    /// How to pick the items to evict once the memory limit is reached
+    #[arg(long, value_enum, default_value_t = PolicyKind::Lru)]
+    eviction_policy: PolicyKind,
 }
 
 #[tokio::main]
 async fn main() {
     print_ascii_art();
-    env_logger::init();
-    let cache: Db = Arc::new(DashMap::with_shard_amount(NUM_SHARDS));
-    let lock_manager: LockManager = Arc::new(DashMap::with_shard_amount(NUM_SHARDS));
+    init_logger();
+    let cache: Db = Arc::new(Store::new());
+    let stats = Arc::new(Stats::new());
     let args = Args::parse();
-    start_cleanup_daemon(cache.clone(), lock_manager.clone()).await;
-    // Start tokio TCP Server
-    match start_server(args.port.unwrap(), cache.clone(), lock_manager.clone()).await {
+    let memory: MemoryManager = Arc::new(Memory::new(
+        args.memory_limit * 1024 * 1024,
+        args.growth_factor,
+        args.eviction_policy,
+    ));
+    start_cleanup_daemon(cache.clone(), stats.clone(), memory.clone()).await;
+    // Start the tokio TCP and unix socket servers
+    match start_server(args, cache.clone(), stats.clone(), memory.clone()).await {
         Ok(_) => (),
         Err(e) => error!("{e}"),
     };
 }
 
+fn parse_growth_factor(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(factor) if factor > 1.0 => Ok(factor),
+        _ => Err("must be a number greater than 1".to_owned()),
+    }
+}
+
+fn init_logger() {
+    // RUST_LOG decides what gets logged, the verbosity command only moves the
+    // level at runtime with log::set_max_level
+    let mut builder = env_logger::Builder::from_default_env();
+    if env::var_os("RUST_LOG").is_none() {
+        // Errors only to start with, but verbosity can turn up our own logs
+        builder
+            .filter_level(LevelFilter::Error)
+            .filter_module("minicache", LevelFilter::Trace);
+        builder.init();
+        log::set_max_level(LevelFilter::Error);
+    } else {
+        builder.init();
+    }
+}
+
 fn print_ascii_art() {
     let art = "
 
@@ -64,50 +160,216 @@ fn print_ascii_art() {
     print!("{}", art);
 }
 
-async fn start_server(port: u16, cache: Db, lock_manager: LockManager) -> Result<()> {
-    let addr = format!("127.0.0.1:{}", port);
-    info!("Starting server on {addr}");
-    let listener = tokio::net::TcpListener::bind(&addr)
-        .await
-        .context(format!("Can't bind {addr}"))?;
+async fn start_server(
+    args: Args,
+    cache: Db,
+    stats: Arc<Stats>,
+    memory: MemoryManager,
+) -> Result<()> {
+    let shutdown = Arc::new(Notify::new());
+    let mut servers = JoinSet::new();
+    for addr in &args.listen {
+        let addr = listen_addr(addr, args.port.unwrap())?;
+        info!("Starting server on {addr}");
+        let listener = bind(addr, Type::STREAM)
+            .and_then(|socket| {
+                socket.listen(1024)?;
+                Ok(TcpListener::from_std(socket.into())?)
+            })
+            .context(format!("Can't bind {addr}"))?;
+        servers.spawn(serve(
+            Listener::Tcp(listener),
+            cache.clone(),
+            stats.clone(),
+            memory.clone(),
+            shutdown.clone(),
+            args.enable_shutdown,
+        ));
+        if args.udp_port != 0 {
+            let addr = SocketAddr::new(addr.ip(), args.udp_port);
+            info!("Starting UDP server on {addr}");
+            let socket = bind(addr, Type::DGRAM)
+                .and_then(|socket| Ok(UdpSocket::from_std(socket.into())?))
+                .context(format!("Can't bind {addr}"))?;
+            servers.spawn(udp::serve(
+                socket,
+                cache.clone(),
+                stats.clone(),
+                memory.clone(),
+                shutdown.clone(),
+                args.enable_shutdown,
+            ));
+        }
+    }
+    if let Some(path) = &args.unix_socket {
+        info!("Starting server on {}", path.display());
+        let listener =
+            bind_unix(path, args.unix_mask).context(format!("Can't bind {}", path.display()))?;
+        servers.spawn(serve(
+            Listener::Unix(listener),
+            cache.clone(),
+            stats.clone(),
+            memory.clone(),
+            shutdown.clone(),
+            args.enable_shutdown,
+        ));
+    }
+    let res = tokio::select! {
+        Some(res) = servers.join_next() => res?,
+        _ = shutdown.notified() => {
+            info!("Shutting down server");
+            Ok(())
+        }
+    };
+    if let Some(path) = &args.unix_socket {
+        let _ = fs::remove_file(path);
+    }
+    res
+}
+
+/// Parses an address to listen on, using the given port if it has none.
+fn listen_addr(addr: &str, port: u16) -> Result<SocketAddr> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    // IPv6 addresses may be bracketed even without a port
+    let ip = addr.trim_start_matches('[').trim_end_matches(']');
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, port)),
+        Err(_) => Err(anyhow!("Invalid listen address {addr}")),
+    }
+}
+
+fn parse_unix_mask(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8).map_err(|_| "must be an octal number like 0700".to_owned())
+}
+
+fn bind(addr: SocketAddr, kind: Type) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, None)?;
+    if addr.is_ipv6() {
+        // Otherwise [::] also takes the IPv4 port and 0.0.0.0 can't be bound next to it
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+fn bind_unix(path: &Path, mask: u32) -> Result<UnixListener> {
+    // A socket left behind by a previous run would make the bind fail
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mask))?;
+    Ok(listener)
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+async fn serve(
+    listener: Listener,
+    cache: Db,
+    stats: Arc<Stats>,
+    memory: MemoryManager,
+    shutdown: Arc<Notify>,
+    enable_shutdown: bool,
+) -> Result<()> {
     loop {
-        let (stream, _) = listener.accept().await?;
-        let cloned_cache = cache.clone();
-        let cloned_lock_manager = lock_manager.clone();
-
-        info!("Accepted new connection");
-        tokio::spawn(async move {
-            let mut connection = Connection::new(stream);
-            loop {
-                let ins = connection.read_instruction().await;
-                match ins {
-                    Ok(ins) => {
-                        match executor::execute(
-                            ins,
-                            cloned_cache.clone(),
-                            cloned_lock_manager.clone(),
-                        ) {
-                            Ok(res) => {
-                                connection.write_line(res).await.unwrap();
-                            }
-                            Err(e) => match e.downcast_ref() {
-                                Some(NetError::ConnClosedByClient) => {
-                                    break;
-                                }
-                                _ => match connection.write_line(e.to_string()).await {
-                                    Ok(_) => {
-                                        continue;
-                                    }
-                                    Err(_) => error!("Failed to write"),
-                                },
-                            },
-                        };
+        match &listener {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(handle_connection(
+                    stream,
+                    cache.clone(),
+                    stats.clone(),
+                    memory.clone(),
+                    shutdown.clone(),
+                    enable_shutdown,
+                ));
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(handle_connection(
+                    stream,
+                    cache.clone(),
+                    stats.clone(),
+                    memory.clone(),
+                    shutdown.clone(),
+                    enable_shutdown,
+                ));
+            }
+        }
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    cache: Db,
+    stats: Arc<Stats>,
+    memory: MemoryManager,
+    shutdown: Arc<Notify>,
+    enable_shutdown: bool,
+) {
+    info!("Accepted new connection");
+    let _connection_guard = stats::ConnectionGuard::new(&stats);
+    let mut connection = Connection::new(stream);
+    process(
+        &mut connection,
+        &cache,
+        &stats,
+        &memory,
+        &shutdown,
+        enable_shutdown,
+    )
+    .await;
+    info!("Dropped Connection");
+}
+
+/// Runs the commands read from the connection until it is closed.
+async fn process<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    cache: &Db,
+    stats: &Arc<Stats>,
+    memory: &MemoryManager,
+    shutdown: &Arc<Notify>,
+    enable_shutdown: bool,
+) {
+    loop {
+        let ins = connection.read_instruction().await;
+        match ins {
+            Ok(ins) => {
+                let noreply = ins.noreply();
+                match executor::execute(ins, cache.clone(), stats.clone(), memory.clone()) {
+                    Ok(_) if noreply => (),
+                    Ok(res) => {
+                        if connection.write_response(&res).await.is_err() {
+                            error!("Failed to write");
+                            break;
+                        }
                     }
                     Err(e) => match e.downcast_ref() {
                         Some(NetError::ConnClosedByClient) => {
                             break;
                         }
-                        _ => match connection.write_line(e.to_string()).await {
+                        Some(NetError::ShutdownRequested) if enable_shutdown => {
+                            shutdown.notify_one();
+                            break;
+                        }
+                        Some(NetError::ShutdownRequested) => {
+                            match connection.write_line(b"ERROR: shutdown not enabled").await {
+                                Ok(_) => continue,
+                                Err(_) => error!("Failed to write"),
+                            }
+                        }
+                        // Errors are still reported to noreply clients
+                        _ => match connection.write_error(&ProtocolError::from_error(&e)).await {
                             Ok(_) => {
                                 continue;
                             }
@@ -116,20 +378,37 @@ async fn start_server(port: u16, cache: Db, lock_manager: LockManager) -> Result
                     },
                 };
             }
-            info!("Dropped Connection");
-        });
+            Err(e) => match e.downcast_ref() {
+                // A broken binary packet leaves no way to find the next one
+                Some(NetError::ConnClosedByClient) | Some(NetError::InvalidPacket) => {
+                    break;
+                }
+                _ => {
+                    let too_long = matches!(e.downcast_ref(), Some(ParseError::LineTooLong));
+                    match connection.write_error(&ProtocolError::from_error(&e)).await {
+                        // What follows the line can't be told apart from the next command
+                        Ok(_) if too_long => break,
+                        Ok(_) => {
+                            continue;
+                        }
+                        Err(_) => error!("Failed to write"),
+                    }
+                }
+            },
+        };
     }
 }
 
-async fn start_cleanup_daemon(cache: Db, lock_manager: LockManager) {
+async fn start_cleanup_daemon(cache: Db, stats: Arc<Stats>, memory: MemoryManager) {
     let cache = cache.clone();
     tokio::spawn(async move {
         loop {
             let cache = cache.clone();
-            let lock_manager = lock_manager.clone();
+            let stats = stats.clone();
+            let memory = memory.clone();
             sleep(Duration::from_secs(CLEANUP_GAP)).await;
 
-            match cleaner::clean(cache, lock_manager).await {
+            match cleaner::clean(cache, stats, memory).await {
                 Ok(_) => sleep(Duration::from_secs(CLEANUP_GAP)).await,
                 Err(e) => match e.downcast_ref() {
                     Some(CleanupError::NeedToRepeat) => {
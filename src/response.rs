@@ -0,0 +1,116 @@
+use bytes::Bytes;
+
+/// A reply produced by the executor, encoded by the connection in the
+/// protocol the client speaks.
+#[derive(Debug, Clone)]
+pub enum Response {
+    /// The item was stored with this CAS value.
+    Stored {
+        cas: u64,
+    },
+    NotStored,
+    Exists,
+    NotFound,
+    Deleted,
+    Touched,
+    Ok,
+    /// A single line such as the stats, sent with a trailing `\r\n`.
+    Line(Bytes),
+    /// The value of a counter after an incr or decr, sent as a line, and the
+    /// CAS value it was stored with.
+    Counter {
+        value: Bytes,
+        cas: u64,
+    },
+    /// The items found by a retrieval command. Stored values are shared with
+    /// the cache rather than copied into the reply.
+    Values {
+        values: Vec<Value>,
+        with_cas: bool,
+    },
+    /// The reply to a meta command: its return code, such as `HD`, the flags
+    /// it returns and, for a `VA` reply, the value.
+    Meta {
+        code: &'static str,
+        flags: Vec<String>,
+        value: Option<Bytes>,
+    },
+    /// Nothing is sent, for a quiet meta command with an expected outcome.
+    Quiet,
+}
+
+#[derive(Debug, Clone)]
+pub struct Value {
+    pub key: String,
+    pub flags: u32,
+    pub cas: u64,
+    pub data: Bytes,
+    pub expiry_timestamp: u128,
+    // Millisecond timestamp of the access before this one
+    pub accessed_timestamp: u128,
+    // Whether the item was fetched before this access
+    pub fetched: bool,
+}
+
+impl Response {
+    /// The frames of the text protocol reply, sent back to back as they are.
+    pub fn text_frames(&self) -> Vec<Bytes> {
+        let line: &'static [u8] = match self {
+            Response::Stored { .. } => b"STORED\r\n",
+            Response::NotStored => b"NOT_STORED\r\n",
+            Response::Exists => b"EXISTS\r\n",
+            Response::NotFound => b"NOT_FOUND\r\n",
+            Response::Deleted => b"DELETED\r\n",
+            Response::Touched => b"TOUCHED\r\n",
+            Response::Ok => b"OK\r\n",
+            Response::Line(line) | Response::Counter { value: line, .. } => {
+                return vec![line.clone(), Bytes::from_static(b"\r\n")]
+            }
+            Response::Values { values, with_cas } => {
+                let mut frames = Vec::with_capacity(values.len() * 3 + 1);
+                for value in values {
+                    let header = if *with_cas {
+                        format!(
+                            "VALUE {} {} {} {}\r\n",
+                            value.key,
+                            value.flags,
+                            value.data.len(),
+                            value.cas
+                        )
+                    } else {
+                        format!(
+                            "VALUE {} {} {}\r\n",
+                            value.key,
+                            value.flags,
+                            value.data.len()
+                        )
+                    };
+                    frames.push(Bytes::from(header));
+                    frames.push(value.data.clone());
+                    frames.push(Bytes::from_static(b"\r\n"));
+                }
+                frames.push(Bytes::from_static(b"END\r\n"));
+                return frames;
+            }
+            Response::Meta { code, flags, value } => {
+                let mut line = match value {
+                    Some(value) => format!("{} {}", code, value.len()),
+                    None => code.to_string(),
+                };
+                for flag in flags {
+                    line.push(' ');
+                    line.push_str(flag);
+                }
+                line.push_str("\r\n");
+                let mut frames = vec![Bytes::from(line)];
+                if let Some(value) = value {
+                    frames.push(value.clone());
+                    frames.push(Bytes::from_static(b"\r\n"));
+                }
+                return frames;
+            }
+            Response::Quiet => return Vec::new(),
+        };
+        vec![Bytes::from_static(line)]
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use bytes::{Bytes, BytesMut};
+
+/// Memory is handed out to the slab classes one page at a time.
+/// No item can be bigger than a page.
+pub const PAGE_SIZE: usize = 1024 * 1024;
+const MIN_CHUNK_SIZE: usize = 64;
+// As in memcached, a growth factor close to 1 cannot make more classes than this
+const MAX_CLASSES: usize = 63;
+const CHUNK_ALIGN: usize = 8;
+
+/// Stores values in fixed size chunks carved out of pages, like memcached.
+///
+/// Each slab class holds chunks of one size, the sizes growing by the growth
+/// factor from one class to the next. An item goes to the smallest class it
+/// fits in. Pages are assigned to a class when it runs out of free chunks and
+/// are never given back, so the memory used stays within the limit, plus the
+/// first page of each class, no matter how the items churn.
+#[derive(Debug)]
+pub struct Slabs {
+    // 0 means pages are assigned without limit
+    max_pages: usize,
+    pages: AtomicUsize,
+    classes: Vec<Arc<SlabClass>>,
+}
+
+#[derive(Debug)]
+struct SlabClass {
+    chunk_size: usize,
+    state: Mutex<ClassState>,
+}
+
+#[derive(Debug, Default)]
+struct ClassState {
+    pages: usize,
+    used_chunks: usize,
+    free: Vec<BytesMut>,
+}
+
+/// A chunk holding a value. It goes back to the free list of its class once
+/// the item and every response still sending the value are gone.
+struct Chunk {
+    buf: BytesMut,
+    class: Arc<SlabClass>,
+}
+
+pub struct ClassStats {
+    pub chunk_size: usize,
+    pub chunks_per_page: usize,
+    pub total_pages: usize,
+    pub used_chunks: usize,
+    pub free_chunks: usize,
+}
+
+impl Slabs {
+    pub fn new(limit: u64, growth_factor: f64) -> Slabs {
+        let mut classes = Vec::new();
+        let mut chunk_size = MIN_CHUNK_SIZE;
+        // The last class, holding whole pages, is always there
+        while chunk_size < PAGE_SIZE && classes.len() < MAX_CLASSES - 1 {
+            classes.push(Arc::new(SlabClass::new(chunk_size)));
+            let grown = align((chunk_size as f64 * growth_factor) as usize);
+            chunk_size = grown.max(chunk_size + CHUNK_ALIGN);
+        }
+        classes.push(Arc::new(SlabClass::new(PAGE_SIZE)));
+        Slabs {
+            max_pages: (limit / PAGE_SIZE as u64) as usize,
+            pages: AtomicUsize::new(0),
+            classes,
+        }
+    }
+
+    pub fn num_classes(&self) -> usize {
+        self.classes.len()
+    }
+
+    /// The smallest class whose chunks can hold `size` bytes.
+    pub fn class_for(&self, size: usize) -> Option<usize> {
+        let class = self
+            .classes
+            .partition_point(|class| class.chunk_size < size);
+        if class < self.classes.len() {
+            Some(class)
+        } else {
+            None
+        }
+    }
+
+    /// Copies the parts one after the other into a free chunk of the class.
+    /// Returns None when the class has no free chunk and no page can be assigned to it.
+    pub fn alloc(&self, class: usize, parts: &[&[u8]]) -> Option<Bytes> {
+        let slab_class = &self.classes[class];
+        let mut state = slab_class
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if state.free.is_empty() {
+            // Like memcached, a class always gets its first page so that no
+            // item size is locked out once the other classes used up the limit
+            if !self.assign_page(state.pages == 0) {
+                return None;
+            }
+            let mut page = BytesMut::zeroed(PAGE_SIZE);
+            for _ in 0..PAGE_SIZE / slab_class.chunk_size {
+                let mut chunk = page.split_to(slab_class.chunk_size);
+                chunk.clear();
+                state.free.push(chunk);
+            }
+            state.pages += 1;
+        }
+        let mut buf = state.free.pop()?;
+        state.used_chunks += 1;
+        drop(state);
+
+        for part in parts {
+            // Never reallocates, the chunk is big enough for the whole value
+            buf.extend_from_slice(part);
+        }
+        Some(Bytes::from_owner(Chunk {
+            buf,
+            class: slab_class.clone(),
+        }))
+    }
+
+    pub fn total_malloced(&self) -> usize {
+        self.pages.load(Ordering::Relaxed) * PAGE_SIZE
+    }
+
+    /// Stats of every class that has at least one page, with its class id.
+    pub fn class_stats(&self) -> Vec<(usize, ClassStats)> {
+        let mut result = Vec::new();
+        for (id, class) in self.classes.iter().enumerate() {
+            let state = class.state.lock().unwrap_or_else(PoisonError::into_inner);
+            if state.pages == 0 {
+                continue;
+            }
+            let chunks_per_page = PAGE_SIZE / class.chunk_size;
+            result.push((
+                id,
+                ClassStats {
+                    chunk_size: class.chunk_size,
+                    chunks_per_page,
+                    total_pages: state.pages,
+                    used_chunks: state.used_chunks,
+                    free_chunks: state.free.len(),
+                },
+            ));
+        }
+        result
+    }
+
+    fn assign_page(&self, first_page: bool) -> bool {
+        self.pages
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pages| {
+                if first_page || self.max_pages == 0 || pages < self.max_pages {
+                    Some(pages + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
+
+impl SlabClass {
+    fn new(chunk_size: usize) -> SlabClass {
+        SlabClass {
+            chunk_size,
+            state: Mutex::new(ClassState::default()),
+        }
+    }
+}
+
+impl AsRef<[u8]> for Chunk {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        let mut buf = mem::take(&mut self.buf);
+        buf.clear();
+        let mut state = self
+            .class
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        state.used_chunks -= 1;
+        state.free.push(buf);
+    }
+}
+
+fn align(size: usize) -> usize {
+    size.div_ceil(CHUNK_ALIGN) * CHUNK_ALIGN
+}
@@ -0,0 +1,176 @@
+use std::{
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+use crate::{eviction::Memory, Db};
+
+#[derive(Debug)]
+pub struct Stats {
+    started_at: Instant,
+    pub curr_connections: AtomicU64,
+    pub total_connections: AtomicU64,
+    pub cmd_get: AtomicU64,
+    pub cmd_set: AtomicU64,
+    pub cmd_flush: AtomicU64,
+    pub cmd_touch: AtomicU64,
+    pub get_hits: AtomicU64,
+    pub get_misses: AtomicU64,
+    pub delete_hits: AtomicU64,
+    pub delete_misses: AtomicU64,
+    pub incr_hits: AtomicU64,
+    pub incr_misses: AtomicU64,
+    pub decr_hits: AtomicU64,
+    pub decr_misses: AtomicU64,
+    pub cas_hits: AtomicU64,
+    pub cas_misses: AtomicU64,
+    pub cas_badval: AtomicU64,
+    pub touch_hits: AtomicU64,
+    pub touch_misses: AtomicU64,
+    pub total_items: AtomicU64,
+    pub expired_unfetched: AtomicU64,
+    pub evictions: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started_at: Instant::now(),
+            curr_connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            cmd_get: AtomicU64::new(0),
+            cmd_set: AtomicU64::new(0),
+            cmd_flush: AtomicU64::new(0),
+            cmd_touch: AtomicU64::new(0),
+            get_hits: AtomicU64::new(0),
+            get_misses: AtomicU64::new(0),
+            delete_hits: AtomicU64::new(0),
+            delete_misses: AtomicU64::new(0),
+            incr_hits: AtomicU64::new(0),
+            incr_misses: AtomicU64::new(0),
+            decr_hits: AtomicU64::new(0),
+            decr_misses: AtomicU64::new(0),
+            cas_hits: AtomicU64::new(0),
+            cas_misses: AtomicU64::new(0),
+            cas_badval: AtomicU64::new(0),
+            touch_hits: AtomicU64::new(0),
+            touch_misses: AtomicU64::new(0),
+            total_items: AtomicU64::new(0),
+            expired_unfetched: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Builds the reply to the `stats` command, one `STAT <name> <value>` line per counter.
+    pub fn report(&self, cache: &Db, memory: &Memory) -> Result<String> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let stats: Vec<(&str, String)> = vec![
+            ("pid", process::id().to_string()),
+            ("uptime", self.started_at.elapsed().as_secs().to_string()),
+            ("time", time.to_string()),
+            ("version", env!("CARGO_PKG_VERSION").to_owned()),
+            ("curr_connections", load(&self.curr_connections)),
+            ("total_connections", load(&self.total_connections)),
+            ("cmd_get", load(&self.cmd_get)),
+            ("cmd_set", load(&self.cmd_set)),
+            ("cmd_flush", load(&self.cmd_flush)),
+            ("cmd_touch", load(&self.cmd_touch)),
+            ("get_hits", load(&self.get_hits)),
+            ("get_misses", load(&self.get_misses)),
+            ("delete_misses", load(&self.delete_misses)),
+            ("delete_hits", load(&self.delete_hits)),
+            ("incr_misses", load(&self.incr_misses)),
+            ("incr_hits", load(&self.incr_hits)),
+            ("decr_misses", load(&self.decr_misses)),
+            ("decr_hits", load(&self.decr_hits)),
+            ("cas_misses", load(&self.cas_misses)),
+            ("cas_hits", load(&self.cas_hits)),
+            ("cas_badval", load(&self.cas_badval)),
+            ("touch_hits", load(&self.touch_hits)),
+            ("touch_misses", load(&self.touch_misses)),
+            ("expired_unfetched", load(&self.expired_unfetched)),
+            ("curr_items", cache.len().to_string()),
+            ("total_items", load(&self.total_items)),
+            ("bytes", memory.used().to_string()),
+            ("limit_maxbytes", memory.limit().to_string()),
+            ("evictions", load(&self.evictions)),
+        ];
+
+        let mut result = String::new();
+        for (name, value) in stats {
+            result.push_str(&format!("STAT {} {}\r\n", name, value));
+        }
+        result.push_str("END");
+        Ok(result)
+    }
+}
+
+/// Builds the reply to `stats slabs`, the stats of each slab class in use
+/// prefixed with its class id, followed by the totals.
+pub fn report_slabs(memory: &Memory) -> String {
+    let slabs = memory.slabs();
+    let classes = slabs.class_stats();
+    let mut result = String::new();
+    for (id, class) in &classes {
+        let stats = [
+            ("chunk_size", class.chunk_size),
+            ("chunks_per_page", class.chunks_per_page),
+            ("total_pages", class.total_pages),
+            ("total_chunks", class.total_pages * class.chunks_per_page),
+            ("used_chunks", class.used_chunks),
+            ("free_chunks", class.free_chunks),
+        ];
+        for (name, value) in stats {
+            // memcached numbers its slab classes from 1
+            result.push_str(&format!("STAT {}:{} {}\r\n", id + 1, name, value));
+        }
+    }
+    result.push_str(&format!("STAT active_slabs {}\r\n", classes.len()));
+    result.push_str(&format!(
+        "STAT total_malloced {}\r\n",
+        slabs.total_malloced()
+    ));
+    result.push_str("END");
+    result
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts an open connection for as long as it lives. The count is released
+/// on drop, so a connection task that panics does not leave it behind.
+pub struct ConnectionGuard<'a> {
+    stats: &'a Stats,
+}
+
+impl<'a> ConnectionGuard<'a> {
+    pub fn new(stats: &'a Stats) -> ConnectionGuard<'a> {
+        incr(&stats.curr_connections);
+        incr(&stats.total_connections);
+        ConnectionGuard { stats }
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        decr(&self.stats.curr_connections);
+    }
+}
+
+pub fn incr(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn decr(counter: &AtomicU64) {
+    counter.fetch_sub(1, Ordering::Relaxed);
+}
+
+fn load(counter: &AtomicU64) -> String {
+    counter.load(Ordering::Relaxed).to_string()
+}
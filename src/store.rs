@@ -0,0 +1,129 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use dashmap::{
+    iter::Iter,
+    mapref::{
+        entry::Entry,
+        one::{Ref, RefMut},
+    },
+    DashMap,
+};
+
+use crate::DBItem;
+
+const NUM_SHARDS: usize = 32;
+const NUM_KEY_LOCKS: usize = 1024;
+
+/// The items of the cache together with their per key locks.
+///
+/// The locks are held by the commands that change an item for as long as
+/// they run, so that read-modify-write commands like append and incr are
+/// atomic. Keys are spread over a fixed set of mutexes, so a lock never has to
+/// be created or removed along with its item and the two can't get out of
+/// step. Two keys may share a mutex, which only costs some waiting. A command
+/// holds at most one key lock at a time.
+///
+/// The store also hands out the CAS values and keeps track of flush_all,
+/// whose items are invalidated lazily.
+pub struct Store {
+    items: DashMap<String, DBItem>,
+    hasher: RandomState,
+    locks: Vec<Mutex<()>>,
+    cas_counter: AtomicU64,
+    // Items with a lower CAS value were flushed by a flush_all without delay
+    flush_cas: AtomicU64,
+    // Millisecond timestamp a delayed flush_all takes effect at, 0 if none is pending
+    flush_timestamp: AtomicU64,
+}
+
+impl Store {
+    pub fn new() -> Store {
+        Store {
+            items: DashMap::with_shard_amount(NUM_SHARDS),
+            hasher: RandomState::new(),
+            locks: (0..NUM_KEY_LOCKS).map(|_| Mutex::new(())).collect(),
+            cas_counter: AtomicU64::new(1),
+            flush_cas: AtomicU64::new(0),
+            flush_timestamp: AtomicU64::new(0),
+        }
+    }
+
+    pub fn next_cas(&self) -> u64 {
+        self.cas_counter.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Invalidates the items stored so far, or with a timestamp in
+    /// milliseconds, the items stored before that time once it has come.
+    pub fn flush(&self, timestamp: Option<u64>) {
+        match timestamp {
+            Some(timestamp) => self.flush_timestamp.store(timestamp, Ordering::Relaxed),
+            None => {
+                // The CAS sequence orders stores that happen in the same millisecond
+                self.flush_cas.store(self.next_cas(), Ordering::Relaxed);
+                self.flush_timestamp.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether a flush_all that has taken effect by `now` invalidated the item.
+    pub fn is_flushed(&self, item: &DBItem, now: u128) -> bool {
+        if item.cas < self.flush_cas.load(Ordering::Relaxed) {
+            return true;
+        }
+        let flush_timestamp = self.flush_timestamp.load(Ordering::Relaxed) as u128;
+        flush_timestamp != 0 && flush_timestamp <= now && item.stored_timestamp < flush_timestamp
+    }
+
+    pub fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let index = self.hasher.hash_one(key) as usize % self.locks.len();
+        // The mutex guards no data, so it is still good after a panic
+        self.locks[index]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Ref<'_, String, DBItem>> {
+        self.items.get(key)
+    }
+
+    pub fn get_mut(&self, key: &str) -> Option<RefMut<'_, String, DBItem>> {
+        self.items.get_mut(key)
+    }
+
+    pub fn entry(&self, key: String) -> Entry<'_, String, DBItem> {
+        self.items.entry(key)
+    }
+
+    pub fn remove(&self, key: &str) -> Option<(String, DBItem)> {
+        self.items.remove(key)
+    }
+
+    pub fn remove_if(
+        &self,
+        key: &str,
+        condition: impl FnOnce(&String, &DBItem) -> bool,
+    ) -> Option<(String, DBItem)> {
+        self.items.remove_if(key, condition)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn iter(&self) -> Iter<'_, String, DBItem> {
+        self.items.iter()
+    }
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new()
+    }
+}
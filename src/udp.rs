@@ -0,0 +1,189 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{error, info};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::Notify,
+};
+
+use crate::{connection::Connection, stats::Stats, Db, MemoryManager};
+
+const FRAME_HEADER_SIZE: usize = 8;
+// Same as memcached, small enough to not be fragmented on most networks
+const MAX_DATAGRAM_SIZE: usize = 1400;
+const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - FRAME_HEADER_SIZE;
+const MAX_REQUEST_SIZE: usize = 65536;
+
+/// The frame header in front of every UDP datagram, all fields big endian.
+struct FrameHeader {
+    request_id: u16,
+    sequence: u16,
+    total: u16,
+}
+
+impl FrameHeader {
+    fn parse(datagram: &[u8]) -> Option<FrameHeader> {
+        if datagram.len() < FRAME_HEADER_SIZE {
+            return None;
+        }
+        let field = |at: usize| u16::from_be_bytes([datagram[at], datagram[at + 1]]);
+        Some(FrameHeader {
+            request_id: field(0),
+            sequence: field(2),
+            total: field(4),
+        })
+    }
+
+    fn put(&self, datagram: &mut BytesMut) {
+        datagram.put_u16(self.request_id);
+        datagram.put_u16(self.sequence);
+        datagram.put_u16(self.total);
+        // Reserved
+        datagram.put_u16(0);
+    }
+}
+
+/// The commands of one request datagram as a stream, collecting the replies.
+#[derive(Debug)]
+struct Datagram {
+    request: Bytes,
+    response: Vec<u8>,
+}
+
+impl AsyncRead for Datagram {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // Reads nothing once the request is used up, which ends the connection
+        let n = buf.remaining().min(self.request.len());
+        let chunk = self.request.split_to(n);
+        buf.put_slice(&chunk);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Datagram {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.response.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Answers the requests sent to the socket, each datagram carrying its own
+/// frame header and one or more commands, like memcached over UDP.
+pub async fn serve(
+    socket: UdpSocket,
+    cache: Db,
+    stats: Arc<Stats>,
+    memory: MemoryManager,
+    shutdown: Arc<Notify>,
+    enable_shutdown: bool,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+    let mut buf = vec![0; MAX_REQUEST_SIZE];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+        let datagram = Bytes::copy_from_slice(&buf[..n]);
+        tokio::spawn(handle_datagram(
+            datagram,
+            peer,
+            socket.clone(),
+            cache.clone(),
+            stats.clone(),
+            memory.clone(),
+            shutdown.clone(),
+            enable_shutdown,
+        ));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_datagram(
+    mut datagram: Bytes,
+    peer: SocketAddr,
+    socket: Arc<UdpSocket>,
+    cache: Db,
+    stats: Arc<Stats>,
+    memory: MemoryManager,
+    shutdown: Arc<Notify>,
+    enable_shutdown: bool,
+) {
+    let header = match FrameHeader::parse(&datagram) {
+        Some(header) => header,
+        None => {
+            info!("Dropped UDP datagram without a frame header from {peer}");
+            return;
+        }
+    };
+    let request = datagram.split_off(FRAME_HEADER_SIZE);
+    let response = if header.sequence != 0 || header.total != 1 {
+        b"SERVER_ERROR multi-packet request not supported\r\n".to_vec()
+    } else {
+        let mut connection = Connection::new(Datagram {
+            request,
+            response: Vec::new(),
+        });
+        crate::process(
+            &mut connection,
+            &cache,
+            &stats,
+            &memory,
+            &shutdown,
+            enable_shutdown,
+        )
+        .await;
+        connection.into_inner().response
+    };
+    if let Err(e) = send_response(&socket, peer, header.request_id, &response).await {
+        error!("Failed to send UDP response to {peer}: {e}");
+    }
+}
+
+/// Sends the response split over as many datagrams as it takes.
+async fn send_response(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    request_id: u16,
+    response: &[u8],
+) -> Result<()> {
+    let mut chunks: Vec<&[u8]> = response.chunks(MAX_PAYLOAD_SIZE).collect();
+    if chunks.len() > u16::MAX as usize {
+        chunks = vec![b"SERVER_ERROR response too large for UDP\r\n"];
+    }
+    let total = chunks.len() as u16;
+    for (sequence, chunk) in chunks.into_iter().enumerate() {
+        let mut datagram = BytesMut::with_capacity(FRAME_HEADER_SIZE + chunk.len());
+        FrameHeader {
+            request_id,
+            sequence: sequence as u16,
+            total,
+        }
+        .put(&mut datagram);
+        datagram.put_slice(chunk);
+        socket.send_to(&datagram, peer).await?;
+    }
+    Ok(())
+}